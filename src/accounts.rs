@@ -0,0 +1,307 @@
+use crate::metadata::now;
+use argon2::Argon2;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs;
+use std::net::{IpAddr, SocketAddr};
+
+// seconds between the heartbeats of a client, and without one before it counts as offline
+pub const HEARTBEAT_INTERVAL: u64 = 5;
+pub const HEARTBEAT_TIMEOUT: u64 = 3 * HEARTBEAT_INTERVAL;
+
+// what a client sends to register or log in
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct Credentials {
+    pub username: String,
+    pub password: String,
+    // only used when registering, the username if empty
+    #[serde(default)]
+    pub display_name: String,
+    // picked by the client for this login, it goes with every request after it
+    #[serde(default)]
+    pub session: String,
+}
+
+// one user as the directory of service shows it
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct UserEntry {
+    pub username: String,
+    pub display_name: String,
+    pub endpoint: Option<IpAddr>,
+    pub public_key: Vec<u8>,
+    pub online: bool,
+    // unix time of the last message from the user
+    pub last_seen: u64,
+    pub shared_images: u32,
+}
+
+// ask for the users whose username or display name has search in it, everyone if empty
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct DirectoryQuery {
+    pub search: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct DirectoryResponse {
+    pub users: Vec<UserEntry>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Account {
+    pub username: String,
+    salt: Vec<u8>,
+    password_hash: Vec<u8>,
+    // key the user signs with from where it logged in last
+    pub public_key: Vec<u8>,
+    // where the user is now, None until it logs in
+    pub endpoint: Option<IpAddr>,
+    #[serde(default)]
+    pub display_name: String,
+    #[serde(default)]
+    pub online: bool,
+    #[serde(default)]
+    pub last_seen: u64,
+    // images the user uploaded to be encoded
+    #[serde(default)]
+    pub shared_images: u32,
+    // port the servers send changes to the directory and kept updates to, only for this login
+    #[serde(skip)]
+    pub subscribed: Option<u16>,
+    // session of the last login, kept so the user is still known after a restart
+    #[serde(default)]
+    session: Option<String>,
+}
+
+impl Account {
+    pub fn entry(&self) -> UserEntry {
+        UserEntry {
+            username: self.username.clone(),
+            display_name: self.display_name.clone(),
+            endpoint: self.endpoint,
+            public_key: self.public_key.clone(),
+            online: self.online,
+            last_seen: self.last_seen,
+            shared_images: self.shared_images,
+        }
+    }
+}
+
+// argon2 with its default cost, so guessing a password from the hash takes a while
+fn hash_password(salt: &[u8], password: &str) -> Vec<u8> {
+    let mut hash = vec![0; 32];
+    Argon2::default()
+        .hash_password_into(password.as_bytes(), salt, &mut hash)
+        .expect("salt and hash have valid lengths");
+    hash
+}
+
+// every account the server knows, saved to a file whenever one changes
+pub struct Accounts {
+    path: String,
+    accounts: HashMap<String, Account>,
+}
+
+impl Accounts {
+    pub fn load(path: &str) -> Accounts {
+        let mut accounts: HashMap<String, Account> = fs::read(path)
+            .ok()
+            .and_then(|bytes| serde_json::from_slice(&bytes).ok())
+            .unwrap_or_default();
+        // nobody is online until it logs in again
+        for account in accounts.values_mut() {
+            account.online = false;
+        }
+        Accounts {
+            path: path.to_string(),
+            accounts,
+        }
+    }
+
+    fn save(&self) {
+        if let Err(e) = fs::write(&self.path, serde_json::to_vec(&self.accounts).unwrap()) {
+            println!("Could not save accounts to {}: {}", self.path, e);
+        }
+    }
+
+    pub fn register(
+        &mut self,
+        credentials: &Credentials,
+        public_key: &[u8],
+        endpoint: IpAddr,
+    ) -> Result<(), String> {
+        let username = credentials.username.trim();
+        if username.is_empty() || username.contains(char::is_whitespace) {
+            return Err("a username can not be empty or have spaces".to_string());
+        }
+        if self.accounts.contains_key(username) {
+            return Err(format!("{} is already taken", username));
+        }
+        let salt: [u8; 16] = rand::random();
+        let display_name = match credentials.display_name.trim() {
+            "" => username,
+            display_name => display_name,
+        };
+        let account = Account {
+            username: username.to_string(),
+            salt: salt.to_vec(),
+            password_hash: hash_password(&salt, &credentials.password),
+            public_key: public_key.to_vec(),
+            endpoint: None,
+            display_name: display_name.to_string(),
+            online: false,
+            last_seen: 0,
+            shared_images: 0,
+            subscribed: None,
+            session: None,
+        };
+        self.accounts.insert(username.to_string(), account);
+        self.login(credentials, public_key, endpoint)
+    }
+
+    // the user is now at endpoint in the session it picked and signs with public_key.
+    // other users may be at the same endpoint, behind the same nat
+    pub fn login(
+        &mut self,
+        credentials: &Credentials,
+        public_key: &[u8],
+        endpoint: IpAddr,
+    ) -> Result<(), String> {
+        let username = credentials.username.trim();
+        match self.accounts.get(username) {
+            Some(account)
+                if account.password_hash == hash_password(&account.salt, &credentials.password) => {
+            }
+            _ => return Err("wrong username or password".to_string()),
+        }
+        if credentials.session.is_empty() {
+            return Err("log in with a session".to_string());
+        }
+        // a session is only ever one user's
+        for account in self.accounts.values_mut() {
+            if account.session.as_deref() == Some(credentials.session.as_str()) {
+                account.session = None;
+            }
+        }
+        let account = self.accounts.get_mut(username).unwrap();
+        account.session = Some(credentials.session.clone());
+        account.public_key = public_key.to_vec();
+        account.endpoint = Some(endpoint);
+        account.online = true;
+        account.last_seen = now();
+        account.subscribed = None;
+        self.save();
+        Ok(())
+    }
+
+    // the user in session just sent us something from ip, it may have moved there.
+    // a user that missed its heartbeats or was online before a restart is online again,
+    // returns if it was not before
+    pub fn seen(&mut self, session: &str, public_key: &[u8], ip: IpAddr) -> bool {
+        let account = match self.user_in_mut(session, public_key) {
+            Some(account) => account,
+            None => return false,
+        };
+        account.last_seen = now();
+        let back = !account.online;
+        account.online = true;
+        if account.endpoint != Some(ip) {
+            account.endpoint = Some(ip);
+            self.save();
+        }
+        back
+    }
+
+    pub fn set_online(&mut self, session: &str, public_key: &[u8], online: bool) {
+        if let Some(account) = self.user_in_mut(session, public_key) {
+            account.online = online;
+        }
+    }
+
+    // the user in session wants every change to the directory sent to port
+    pub fn subscribe(&mut self, session: &str, public_key: &[u8], port: u16) {
+        if let Some(account) = self.user_in_mut(session, public_key) {
+            account.subscribed = Some(port);
+        }
+    }
+
+    // where to send a change to username, everyone online that subscribed but the user itself
+    pub fn subscribers(&self, username: &str) -> Vec<SocketAddr> {
+        self.accounts
+            .values()
+            .filter(|account| account.online && account.username != username)
+            .filter_map(|account| Some(SocketAddr::new(account.endpoint?, account.subscribed?)))
+            .collect()
+    }
+
+    // every user online that subscribed, with where it gets what the servers push to it
+    pub fn subscribed(&self) -> Vec<(String, SocketAddr)> {
+        self.accounts
+            .values()
+            .filter(|account| account.online)
+            .filter_map(|account| {
+                let addr = SocketAddr::new(account.endpoint?, account.subscribed?);
+                Some((account.username.clone(), addr))
+            })
+            .collect()
+    }
+
+    // users that were online but sent nothing in timeout seconds are offline now
+    pub fn expire(&mut self, timeout: u64) -> Vec<String> {
+        let now = now();
+        let mut expired = Vec::new();
+        for account in self.accounts.values_mut() {
+            if account.online && now.saturating_sub(account.last_seen) > timeout {
+                account.online = false;
+                expired.push(account.username.clone());
+            }
+        }
+        expired
+    }
+
+    // the user uploaded another image
+    pub fn count_image(&mut self, username: &str) {
+        if let Some(account) = self.accounts.get_mut(username) {
+            account.shared_images += 1;
+            self.save();
+        }
+    }
+
+    fn user_in_mut(&mut self, session: &str, public_key: &[u8]) -> Option<&mut Account> {
+        if session.is_empty() {
+            return None;
+        }
+        self.accounts.values_mut().find(|account| {
+            account.session.as_deref() == Some(session) && account.public_key == public_key
+        })
+    }
+
+    // users whose username or display name has the search in it, sorted by username
+    pub fn search(&self, query: &DirectoryQuery) -> DirectoryResponse {
+        let search = query.search.trim().to_lowercase();
+        let mut users: Vec<UserEntry> = self
+            .accounts
+            .values()
+            .filter(|account| {
+                account.username.to_lowercase().contains(&search)
+                    || account.display_name.to_lowercase().contains(&search)
+            })
+            .map(Account::entry)
+            .collect();
+        users.sort_by(|a, b| a.username.cmp(&b.username));
+        DirectoryResponse { users }
+    }
+
+    pub fn get(&self, username: &str) -> Option<&Account> {
+        self.accounts.get(username)
+    }
+
+    // the user logged in with session, only if the request is signed with the key it logged in with
+    pub fn user_in(&self, session: &str, public_key: &[u8]) -> Option<&Account> {
+        if session.is_empty() {
+            return None;
+        }
+        self.accounts.values().find(|account| {
+            account.session.as_deref() == Some(session) && account.public_key == public_key
+        })
+    }
+}
@@ -0,0 +1,136 @@
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::net::IpAddr;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+#[derive(Serialize, Deserialize, Default)]
+struct Keys {
+    // the key every ip is known by now
+    by_ip: HashMap<IpAddr, Vec<u8>>,
+    // every key that sent us an encrypted message, so the node with it knows our key
+    sealing: HashSet<Vec<u8>>,
+    // ips whose first key is kept even when the ring is not pinned
+    pinned: HashSet<IpAddr>,
+}
+
+// public key of every node we know, by ip since a node talks from several ports.
+// kept in a file, so after a restart we still encrypt to every node we knew
+pub struct KeyRing {
+    path: Option<String>,
+    keys: Keys,
+    // the first key of an ip is kept, otherwise the newest one is
+    pinned: bool,
+}
+
+impl KeyRing {
+    pub fn new() -> KeyRing {
+        KeyRing {
+            path: None,
+            keys: Keys::default(),
+            pinned: true,
+        }
+    }
+
+    pub fn load(path: &str) -> KeyRing {
+        let keys = fs::read(path)
+            .ok()
+            .and_then(|bytes| serde_json::from_slice(&bytes).ok())
+            .unwrap_or_default();
+        KeyRing {
+            path: Some(path.to_string()),
+            keys,
+            pinned: true,
+        }
+    }
+
+    // for the servers' clients and a client's peers, they are known by their login and the key
+    // they logged in with. users behind one nat share an ip and a client may come back with a new key
+    pub fn load_unpinned(path: &str) -> KeyRing {
+        KeyRing {
+            pinned: false,
+            ..KeyRing::load(path)
+        }
+    }
+
+    fn save(&self) {
+        if let Some(path) = &self.path {
+            if let Err(e) = fs::write(path, serde_json::to_vec(&self.keys).unwrap()) {
+                println!("Could not save keys to {}: {}", path, e);
+            }
+        }
+    }
+
+    fn insert(&mut self, ip: IpAddr, key: &[u8]) {
+        if self.keys.by_ip.get(&ip).map(Vec::as_slice) != Some(key) {
+            self.keys.by_ip.insert(ip, key.to_vec());
+            self.save();
+        }
+    }
+
+    // a key from the directory of service, it replaces whatever we saw before
+    pub fn learn(&mut self, ip: IpAddr, key: Vec<u8>) {
+        self.insert(ip, &key);
+    }
+
+    // the servers, whoever else is at the ip, only the first key is theirs
+    pub fn pin(&mut self, ip: IpAddr) {
+        if self.keys.pinned.insert(ip) {
+            self.save();
+        }
+    }
+
+    pub fn get(&self, ip: &IpAddr) -> Option<&Vec<u8>> {
+        self.keys.by_ip.get(ip)
+    }
+
+    // the node with the key sent us an encrypted message, from any ip
+    pub fn seals(&self, key: &[u8]) -> bool {
+        self.keys.sealing.contains(key)
+    }
+
+    pub fn sealed_by(&mut self, key: &[u8]) {
+        if self.keys.sealing.insert(key.to_vec()) {
+            self.save();
+        }
+    }
+
+    // the first key a node signs with is kept until the directory says otherwise,
+    // after that anything signed with another key is spoofed.
+    // unpinned, every key is taken and the newest is what we encrypt to the ip with
+    pub fn check(&mut self, ip: IpAddr, key: &[u8]) -> bool {
+        match self.keys.by_ip.get(&ip) {
+            Some(known) if self.pinned || self.keys.pinned.contains(&ip) => known == key,
+            _ => {
+                self.insert(ip, key);
+                true
+            }
+        }
+    }
+}
+
+impl Default for KeyRing {
+    fn default() -> Self {
+        KeyRing::new()
+    }
+}
+
+// milliseconds since the epoch, the owner puts it in every view update it signs
+pub fn timestamp() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or(0)
+}
+
+// what the owner signs when it changes the views of an image it sent,
+// the update may reach the recipient through the servers.
+// issued is when it was signed, the recipient only takes updates newer than the last one
+pub fn view_update_bytes(image: &str, recipient: &str, views: i32, issued: u64) -> Vec<u8> {
+    format!("views:{}:{}:{}:{}", image, recipient, views, issued).into_bytes()
+}
+
+// what the owner signs when it takes an image back, nothing it signs later gives it back
+pub fn revoke_bytes(image: &str, recipient: &str) -> Vec<u8> {
+    format!("revoke:{}:{}", image, recipient).into_bytes()
+}
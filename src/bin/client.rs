@@ -1,36 +1,232 @@
 use base64::{decode, encode};
+use client_server_chat::accounts::{
+    Credentials, DirectoryQuery, DirectoryResponse, UserEntry, HEARTBEAT_INTERVAL,
+};
+use client_server_chat::auth::{self, KeyRing};
+use client_server_chat::crypto;
+use client_server_chat::groups::{Group, GroupRequest};
+use client_server_chat::identity::{self, Identity};
+use client_server_chat::inbox::{Decision, Inbox, Rule, DEFAULT_VIEWS};
+use client_server_chat::integrity;
+use client_server_chat::mailbox::{GroupMailHeader, MailHeader};
+use client_server_chat::metadata::{self, ImageMetadata, Policy};
+use client_server_chat::peers::Peers;
+use client_server_chat::quota::{AuditLog, ViewLedger};
+use client_server_chat::relay::{Relayed, DIRECT_TIMEOUT};
+use client_server_chat::stego;
+use client_server_chat::transport::SecureSocket;
 use image::{buffer, DynamicImage, GenericImageView, Rgba};
 use serde::{Deserialize, Serialize};
 use show_image::*;
 use std::collections::HashSet as Hashset;
+use std::collections::{BTreeMap, HashMap};
 use std::fs;
 use std::fs::File;
 use std::io::{Read, Write};
 use std::net::{IpAddr, Ipv4Addr, SocketAddr, SocketAddrV4, UdpSocket};
 use std::sync::mpsc::{self, Receiver, Sender};
 use std::sync::{Arc, Mutex};
-use std::time::Duration;
+use std::time::{Duration, Instant};
+use std::{fmt, io};
 use std::{mem, process, str, thread};
-use steganography::decoder::*;
-use steganography::encoder::*;
-use steganography::util::*;
 
-fn create_socket(client_ip: &str, port: u16) -> UdpSocket {
+// every socket signs what it sends with our identity and checks what it gets against the keyring,
+// traffic to nodes whose key we know is also encrypted
+fn create_socket(
+    client_ip: &str,
+    port: u16,
+    identity: &Arc<Identity>,
+    keyring: &Arc<Mutex<KeyRing>>,
+) -> SecureSocket {
     let client_address = format!("{}:{}", client_ip, port);
     let socket_addr: SocketAddr = client_address
         .parse()
         .expect("Failed to parse socket address");
-    UdpSocket::bind(socket_addr).expect("Failed to bind socket")
+    let socket = UdpSocket::bind(socket_addr).expect("Failed to bind socket");
+    SecureSocket::new(socket, Arc::clone(identity), Arc::clone(keyring))
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+// sends to peers straight away, or through the servers to peers that did not answer us directly
+struct PeerLink {
+    socket: SecureSocket,
+    servers: Vec<String>,
+    request_type_relay: u8,
+    session: String,
+    // username of every peer we only reach through the servers, by where it is
+    relayed: Arc<Mutex<HashMap<String, String>>>,
+    // when we last got something from a peer directly, by where it is
+    heard: Arc<Mutex<HashMap<String, Instant>>>,
+}
+
+impl PeerLink {
+    fn new(
+        socket: SecureSocket,
+        servers: &[&str],
+        request_type_relay: u8,
+        session: &str,
+    ) -> PeerLink {
+        PeerLink {
+            socket,
+            servers: servers.iter().map(|server| server.to_string()).collect(),
+            request_type_relay,
+            session: session.to_string(),
+            relayed: Arc::new(Mutex::new(HashMap::new())),
+            heard: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    fn try_clone(&self) -> io::Result<PeerLink> {
+        Ok(PeerLink {
+            socket: self.socket.try_clone()?,
+            servers: self.servers.clone(),
+            request_type_relay: self.request_type_relay,
+            session: self.session.clone(),
+            relayed: Arc::clone(&self.relayed),
+            heard: Arc::clone(&self.heard),
+        })
+    }
+
+    fn send_to<A: fmt::Display>(&self, buf: &[u8], dest: A) -> io::Result<usize> {
+        let dest = dest.to_string();
+        let to = match self.relayed.lock().unwrap().get(&dest) {
+            Some(to) => to.clone(),
+            None => return self.socket.send_to(buf, dest.as_str()),
+        };
+        let relayed = Relayed {
+            to,
+            message: String::from_utf8_lossy(buf).to_string(),
+        };
+        let image_fragment = ImageFragment {
+            fragment: serde_json::to_vec(&relayed).unwrap(),
+            request_type: self.request_type_relay,
+            session: self.session.clone(),
+            ..Default::default()
+        };
+        let encoded = serde_json::to_string(&image_fragment).unwrap();
+        for server in &self.servers {
+            self.socket.send_to(encoded.as_bytes(), server.as_str())?;
+        }
+        Ok(buf.len())
+    }
+
+    // a message the peer answers. a peer behind a NAT never answers directly, if it does not
+    // in time this goes to it through the servers and so does everything after it
+    fn request<A: fmt::Display>(&self, buf: &[u8], dest: A, username: &str) -> io::Result<usize> {
+        let dest = dest.to_string();
+        let sent = Instant::now();
+        let len = self.send_to(buf, &dest)?;
+        if self.is_relayed(&dest) {
+            return Ok(len);
+        }
+        let link = self.try_clone()?;
+        let buf = buf.to_vec();
+        let username = username.to_string();
+        thread::spawn(move || {
+            thread::sleep(Duration::from_secs(DIRECT_TIMEOUT));
+            if link.heard_since(&dest, sent) || link.is_relayed(&dest) {
+                return;
+            }
+            println!("{} did not answer, going through the servers", username);
+            link.relay(&dest, &username);
+            link.send_to(&buf, &dest)
+                .expect("Failed to send data to server");
+        });
+        Ok(len)
+    }
+
+    // everything to the peer at endpoint goes through the servers from now on
+    fn relay(&self, endpoint: &str, username: &str) {
+        self.relayed
+            .lock()
+            .unwrap()
+            .insert(endpoint.to_string(), username.to_string());
+    }
+
+    fn is_relayed(&self, endpoint: &str) -> bool {
+        self.relayed.lock().unwrap().contains_key(endpoint)
+    }
+
+    fn heard(&self, endpoint: &str) {
+        self.heard
+            .lock()
+            .unwrap()
+            .insert(endpoint.to_string(), Instant::now());
+    }
+
+    fn heard_since(&self, endpoint: &str, since: Instant) -> bool {
+        self.heard
+            .lock()
+            .unwrap()
+            .get(endpoint)
+            .is_some_and(|heard| *heard >= since)
+    }
+}
+
+// if addr is one of the servers
+fn is_server(servers: &[&str], addr: SocketAddr) -> bool {
+    servers
+        .iter()
+        .any(|server| server.parse::<SocketAddr>().map(|server| server.ip()) == Ok(addr.ip()))
+}
+
+// a message a peer sent us through the servers, with the username of the peer
+fn relayed_message(message: &MessageType) -> Option<(String, MessageType)> {
+    if message.id != 17 {
+        return None;
+    }
+    let inner = serde_json::from_slice(&message.image_fragment).ok()?;
+    Some((message.message.clone(), inner))
+}
+
+// a change to the views of an image we hold, as its owner signed it
+struct ViewChange<'a> {
+    image: &'a str,
+    recipient: &'a str,
+    views: i32,
+    revoke: bool,
+    issued: u64,
+    signature: &'a [u8],
+}
+
+// a change to the views of an image we hold has to be signed by the user that sent it,
+// even when it comes through the servers. its key is the one the directory has for it
+fn signed_by_owner(directory_of_service: &Peers, owner: &str, change: &ViewChange) -> bool {
+    let signed = if change.revoke {
+        auth::revoke_bytes(change.image, change.recipient)
+    } else {
+        auth::view_update_bytes(change.image, change.recipient, change.views, change.issued)
+    };
+    match directory_of_service.get(owner) {
+        Some(owner) => identity::verify(&owner.public_key, &signed, change.signature),
+        None => false,
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Default)]
 // struct that contains image fragment and request type
 struct ImageFragment {
     fragment: Vec<u8>,
     request_type: u8,
+    // stego codec the uploader wants its image encoded with, 0 lets the server pick
+    #[serde(default)]
+    codec: u8,
+    #[serde(default)]
+    codec_bits: u8,
+    // cover from the server's gallery, 0 means an uploaded cover or the server picks
+    #[serde(default)]
+    cover_id: u8,
+    // sha-256 of the whole upload, only on the last fragment
+    #[serde(default)]
+    checksum: Vec<u8>,
+    // the owner's signed metadata, only on the last fragment of an image
+    #[serde(default)]
+    metadata: Option<ImageMetadata>,
+    // the session we logged in with, the servers know who we are by it
+    #[serde(default)]
+    session: String,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Default)]
 struct MessageType {
     message: String,
     id: u8,
@@ -39,11 +235,32 @@ struct MessageType {
     name: String,
     is_sample: bool,
     sample_num: u8,
+    // sha-256 of the whole transfer, only on the last fragment
+    #[serde(default)]
+    checksum: Vec<u8>,
+    // the owner's signature on a view grant
+    #[serde(default)]
+    signature: Vec<u8>,
+    // the owner takes the image back, with views 0
+    #[serde(default)]
+    revoke: bool,
+    // when the owner signed a view change, so an old one can not be sent again
+    #[serde(default)]
+    issued: u64,
+    // our login, only on a view change we leave with the servers
+    #[serde(default)]
+    session: String,
 }
 
-fn open_image(image_path: &str) {
-    // Load the image file
-    let img = image::open(image_path).unwrap();
+fn open_image(image_bytes: &[u8]) {
+    // Load the image from memory, nothing is written to disk
+    let img = match image::load_from_memory(image_bytes) {
+        Ok(img) => img,
+        Err(e) => {
+            println!("Could not open image: {}", e);
+            return;
+        }
+    };
 
     // Convert the image to RGBA format
     let rgba_image = img.to_rgba8();
@@ -62,23 +279,382 @@ fn open_image(image_path: &str) {
     window.set_image("image-001", image);
 
     thread::sleep(Duration::from_secs(2));
+}
+
+// send bytes to every server in fragments, like the image uploads always did
+fn send_fragments_to_servers(
+    socket: &SecureSocket,
+    servers: &[&str],
+    bytes: &[u8],
+    request_type: u8,
+) {
+    for (j, chunk) in bytes.chunks(1024).enumerate() {
+        let image_fragment = ImageFragment {
+            fragment: chunk.to_vec(),
+            request_type,
+            ..Default::default()
+        };
+        let encoded = serde_json::to_string(&image_fragment).unwrap();
+        for server in servers {
+            socket
+                .send_to(encoded.as_bytes(), server)
+                .expect("Failed to send data to server");
+        }
+        if j % 15 == 0 {
+            thread::sleep(Duration::from_millis(20));
+        }
+    }
+}
+
+// send a request to every server and wait for the leader to answer with the same request type,
+// or with an error and the reason
+fn ask_leader(
+    sending_socket: &SecureSocket,
+    recieving_socket: &SecureSocket,
+    servers: &[&str],
+    request: &ImageFragment,
+    request_type_error: u8,
+) -> Result<Vec<u8>, String> {
+    let encoded = serde_json::to_string(request).unwrap();
+    for server in servers {
+        sending_socket
+            .send_to(encoded.as_bytes(), server)
+            .expect("Failed to send data to server");
+    }
+    recieving_socket
+        .set_read_timeout(Some(Duration::from_secs(5)))
+        .unwrap();
+    let result = loop {
+        let mut buffer = [0; 65535];
+        let amt = match recieving_socket.recv_from(&mut buffer) {
+            Ok((amt, _)) => amt,
+            Err(_) => break Err("the servers did not answer".to_string()),
+        };
+        let reply: ImageFragment =
+            match serde_json::from_str(str::from_utf8(&buffer[..amt]).unwrap()) {
+                Ok(reply) => reply,
+                Err(_) => continue,
+            };
+        if reply.request_type == request.request_type {
+            break Ok(reply.fragment);
+        }
+        if reply.request_type == request_type_error {
+            break Err(String::from_utf8_lossy(&reply.fragment).to_string());
+        }
+    };
+    recieving_socket.set_read_timeout(None).unwrap();
+    result
+}
+
+// our groups with the views every member gets
+fn print_groups(groups: &[Group]) {
+    if groups.is_empty() {
+        println!("You have no groups");
+    }
+    for group in groups {
+        println!("{}:", group.name);
+        for (member, views) in &group.members {
+            println!("  {} with {} views", member, views);
+        }
+    }
+}
 
-    // delete_image(image_path);
+// send an image to a peer in fragments with the given id, the caller sends the end
+fn send_chunks(socket: &PeerLink, dest: &str, bytes: &[u8], id: u8) {
+    for (j, chunk) in bytes.chunks(1024).enumerate() {
+        let image_fragment = MessageType {
+            message: String::new(),
+            id,
+            image_fragment: chunk.to_vec(),
+            ..Default::default()
+        };
+        let encoded = serde_json::to_string(&image_fragment).unwrap();
+        socket
+            .send_to(encoded.as_bytes(), dest)
+            .expect("Failed to send data to client");
+        if j % 10 == 0 && j != 0 {
+            thread::sleep(Duration::from_millis(50));
+        }
+    }
 }
 
-fn delete_image(image_path: &str) {
-    fs::remove_file(image_path);
+// small preview of an image, sent as a sample before the real one is requested
+fn make_sample(image_bytes: &[u8]) -> Option<Vec<u8>> {
+    let image = image::load_from_memory(image_bytes).ok()?;
+    let mut sample = Vec::new();
+    image
+        .thumbnail(64, 64)
+        .write_to(
+            &mut std::io::Cursor::new(&mut sample),
+            image::ImageOutputFormat::Png,
+        )
+        .ok()?;
+    Some(sample)
 }
 
-fn print_DOS(directory_of_service: &Hashset<String>) {
+fn print_DOS(users: &[UserEntry]) {
     println!("Directory of service:");
     let mut num = 1;
-    for ip in directory_of_service.clone() {
-        println!("{}: {}", num, ip);
+    for user in users {
+        let endpoint = match user.endpoint {
+            Some(ip) => ip.to_string(),
+            None => "nowhere".to_string(),
+        };
+        println!(
+            "{}: {} ({}) at {}, {}, last seen at {}, {} shared images",
+            num,
+            user.display_name,
+            user.username,
+            endpoint,
+            if user.online { "online" } else { "offline" },
+            user.last_seen,
+            user.shared_images
+        );
         num += 1;
     }
 }
 
+// send the owner's answer to a view request (id 7), an approval is signed like a view change.
+// a requester that is offline gets it when the directory says it is back
+fn send_decision(
+    link: &PeerLink,
+    peers: &Peers,
+    inbox: &mut Inbox,
+    view_ledger: &Mutex<ViewLedger>,
+    identity: &Identity,
+    mut decision: Decision,
+) {
+    // an image that was taken back gets no more views
+    if decision.views > 0
+        && !view_ledger
+            .lock()
+            .unwrap()
+            .set_views(decision.image, &decision.to, decision.views)
+    {
+        println!(
+            "{} has no views of image {} to add to",
+            decision.to, decision.image
+        );
+        decision.views = 0;
+    }
+    let endpoint = match peers.get(&decision.to) {
+        Some(peer) if peer.online => peer.endpoint.clone().unwrap_or_default(),
+        _ => {
+            println!(
+                "{} is offline, the answer is sent when it is back",
+                decision.to
+            );
+            inbox.keep(decision);
+            return;
+        }
+    };
+    let approved = decision.views > 0;
+    let issued = auth::timestamp();
+    let message = MessageType {
+        message: if approved { "yes" } else { "no" }.to_string(),
+        id: 7,
+        views: decision.views,
+        name: decision.image.to_string(),
+        signature: if approved {
+            identity.sign(&auth::view_update_bytes(
+                &decision.image.to_string(),
+                &decision.to,
+                decision.views,
+                issued,
+            ))
+        } else {
+            Vec::new()
+        },
+        issued,
+        ..Default::default()
+    };
+    println!(
+        "Sending {} for image {} to {}",
+        message.message, decision.image, decision.to
+    );
+    let encoded = serde_json::to_string(&message).unwrap();
+    link.send_to(encoded.as_bytes(), &endpoint)
+        .expect("Failed to send data to client");
+}
+
+// the owner stamps every view change with when it signed it, one that is not newer than the
+// last we took from the owner for the image is an old one sent again
+fn is_newer(
+    latest: &mut HashMap<(String, i32), u64>,
+    owner: &str,
+    image: i32,
+    issued: u64,
+) -> bool {
+    let last = latest.entry((owner.to_string(), image)).or_insert(0);
+    if issued <= *last {
+        return false;
+    }
+    *last = issued;
+    true
+}
+
+// the same image sealed under a new key, in the same cover and with the same metadata.
+// returns the encoded image and its new key
+fn reseal(encoded_image: &[u8], key: &[u8]) -> Option<(Vec<u8>, Vec<u8>)> {
+    let image = image::load_from_memory(encoded_image).ok()?.to_rgba8();
+    let payload = stego::decode(&image)?;
+    let (image_metadata, sealed) = metadata::unpack(&payload);
+    let decoded_image = crypto::open(key, sealed)?;
+    let new_key = crypto::generate_key();
+    let sealed = crypto::seal(&new_key, &decoded_image);
+    let payload = match &image_metadata {
+        Some(image_metadata) => metadata::pack(image_metadata, &sealed),
+        None => sealed,
+    };
+    let image = stego::reencode(&image, &payload).ok()?;
+    Some((stego::to_png(&image), new_key))
+}
+
+// how the image is hidden in its cover: nothing or "alpha" for the alpha channel,
+// "lsb <bits>" for the lowest bits of every color
+fn parse_codec(input: &str) -> Result<(u8, u8), String> {
+    let mut words = input.split_whitespace();
+    match (words.next(), words.next()) {
+        (None, _) | (Some("alpha"), None) => Ok((stego::CODEC_ALPHA, 8)),
+        (Some("lsb"), bits) => {
+            let bits = bits
+                .unwrap_or("1")
+                .parse::<u8>()
+                .map_err(|_| "not a number of bits")?;
+            if bits == 0 || bits > 8 {
+                return Err("lsb takes 1 to 8 bits".to_string());
+            }
+            Ok((stego::CODEC_LSB, bits))
+        }
+        _ => Err(format!("{} is not a codec", input.trim())),
+    }
+}
+
+// how an uploaded image expires: nothing, "at <unix time>", "after <hours>" or "once"
+fn parse_policy(input: &str) -> Result<Policy, String> {
+    let mut policy = Policy::default();
+    let mut words = input.split_whitespace();
+    match (words.next(), words.next()) {
+        (None, _) => {}
+        (Some("once"), None) => policy.view_once = true,
+        (Some("at"), Some(time)) => {
+            let time = time.parse::<u64>().map_err(|_| "not a unix time")?;
+            policy.expires_at = Some(time);
+        }
+        (Some("after"), Some(hours)) => {
+            let hours = hours.parse::<u64>().map_err(|_| "not a number of hours")?;
+            policy.expires_after = Some(hours * 60 * 60);
+        }
+        _ => {
+            return Err(format!(
+                "{} is not a way for an image to expire",
+                input.trim()
+            ))
+        }
+    }
+    Ok(policy)
+}
+
+// the peers we know, with the id, nickname or username they can be picked by
+fn print_peers(peers: &Peers) {
+    println!("Peers:");
+    for peer in peers.list() {
+        println!(
+            "{}: {}{} ({}) {}",
+            peer.id,
+            if peer.favorite { "* " } else { "" },
+            peer.name(),
+            peer.username,
+            if peer.online { "online" } else { "offline" }
+        );
+    }
+}
+
+// put everyone in a directory response but us in the directory of service, with their keys
+fn learn_directory(
+    response: &DirectoryResponse,
+    username: &str,
+    listening_port: u16,
+    directory_of_service: &mut Peers,
+    keyring: &Mutex<KeyRing>,
+) {
+    for user in &response.users {
+        if user.username == username {
+            continue;
+        }
+        if let Some(ip) = user.endpoint {
+            keyring.lock().unwrap().learn(ip, user.public_key.clone());
+        }
+        // the peer is at its ip with the listening port
+        directory_of_service.learn(user, listening_port);
+    }
+}
+
+// ask the servers for the users that match search, only the leader answers
+fn query_directory(
+    sending_socket: &SecureSocket,
+    recieving_socket: &SecureSocket,
+    servers: &[&str],
+    search: &str,
+    request_type_directory: u8,
+) -> DirectoryResponse {
+    let query = DirectoryQuery {
+        search: search.to_string(),
+    };
+    let directory_request = ImageFragment {
+        fragment: serde_json::to_vec(&query).unwrap(),
+        request_type: request_type_directory,
+        ..Default::default()
+    };
+    let encoded = serde_json::to_string(&directory_request).unwrap();
+    for server in servers {
+        sending_socket
+            .send_to(encoded.as_bytes(), server)
+            .expect("Failed to send data to server");
+    }
+    // nobody is found if the servers do not answer
+    recieving_socket
+        .set_read_timeout(Some(Duration::from_secs(5)))
+        .unwrap();
+    let response = loop {
+        let mut buffer = [0; 65535];
+        let amt = match recieving_socket.recv_from(&mut buffer) {
+            Ok((amt, _)) => amt,
+            Err(_) => {
+                println!("The servers did not answer");
+                break DirectoryResponse::default();
+            }
+        };
+        let image_fragment: ImageFragment =
+            match serde_json::from_str(str::from_utf8(&buffer[..amt]).unwrap()) {
+                Ok(image_fragment) => image_fragment,
+                Err(_) => continue,
+            };
+        if image_fragment.request_type == request_type_directory {
+            break serde_json::from_slice(&image_fragment.fragment).unwrap_or_default();
+        }
+    };
+    recieving_socket.set_read_timeout(None).unwrap();
+    response
+}
+
+// username of the client that signs with key, the endpoint it is at if it is not in the directory.
+// users behind one nat are at the same endpoint, their keys tell them apart
+fn username_of(directory_of_service: &Peers, key: &[u8], endpoint: &str) -> String {
+    directory_of_service
+        .with_key(key)
+        .map(|peer| peer.username.clone())
+        .unwrap_or_else(|| endpoint.to_string())
+}
+
+// where a user is now, a user that is not in the directory is taken to be an endpoint already
+fn endpoint_of(directory_of_service: &Peers, user: &str) -> String {
+    directory_of_service
+        .get(user)
+        .and_then(|peer| peer.endpoint.clone())
+        .unwrap_or_else(|| user.to_string())
+}
+
 #[show_image::main]
 fn main() {
     let client_num: u16 = std::env::args()
@@ -103,8 +679,28 @@ fn main() {
     // between clients
     let listening_port = 5555;
     let sending_port = 6666;
-    let client_send_socket = create_socket(client_ip, sending_port);
-    let client_listen_socket = create_socket(client_ip, listening_port);
+    // our own keypair, everything we send, images we upload and the views we grant are
+    // signed with it. the keyring has the keys of everyone else
+    let identity = Arc::new(Identity::load_or_create(&format!(
+        "identity_client_{}.key",
+        client_num
+    )));
+    let keyring: Arc<Mutex<KeyRing>> = Arc::new(Mutex::new(KeyRing::load_unpinned(&format!(
+        "keys_client_{}.json",
+        client_num
+    ))));
+    // drop everything peers send us directly, like a client behind a NAT
+    let unreachable = std::env::args().any(|arg| arg == "--unreachable");
+    if unreachable {
+        println!("Only taking messages from peers through the servers");
+    }
+    println!(
+        "Client identity: {}",
+        identity::fingerprint(&identity.public_key())
+    );
+
+    let client_send_socket = create_socket(client_ip, sending_port, &identity, &keyring);
+    let client_listen_socket = create_socket(client_ip, listening_port, &identity, &keyring);
 
     let server_1_socket = "192.168.1.6:3333"; // SHAALAN MACBOOK
     let server_2_socket = "192.168.1.4:3333"; // ZIZO YOGA
@@ -112,25 +708,201 @@ fn main() {
 
     // client sends to server on port 3333
     // client receives from server on port 9999
-    let sending_socket = create_socket(client_ip, 3333);
-    let recieving_socket = create_socket(client_ip, 9999);
+    let sending_socket = create_socket(client_ip, 3333, &identity, &keyring);
+    let recieving_socket = create_socket(client_ip, 9999, &identity, &keyring);
 
     // request type to server:
     // 1. send image
     // 2. ask for directory of service
+    // 3. key of the image the server just encoded for us
+    // 4. the server could not encode our image
+    // 5. fragment of our own cover image
+    // 6. ask for the cover gallery
+    // 7. a transfer arrived corrupted, send it again
+    // 8. hello, every server answers so we know its key before logging in
+    // 9. register a new user
+    // 10. log in
+    // 11. heartbeat, the servers take us offline when they stop
+    // 12. leave, we go offline now
+    // 13. join, we are back and want the view changes kept for us
+    // 14. subscribe, the leader sends us every change to the directory from now on
+    // 15. ack, we got a view change the servers kept for us
+    // 16. fragment of an image the servers keep for a user until it picks it up
+    // 17. end of that image, the fragment says who it is for
+    // 18. we got an image the servers kept for us
+    // 19. a message for a peer we can not reach, the leader passes it on
+    // 20. a change to our groups, the leader answers with all of them
+    // 21. the encoded image arrived whole, the leader stops keeping it and its key
+    // 22. the end of an image for every member of one of our groups, uploaded only once
     let request_type_image = 1;
     let request_type_directory: u8 = 2;
+    let request_type_key: u8 = 3;
+    let request_type_error: u8 = 4;
+    let request_type_cover: u8 = 5;
+    let request_type_gallery: u8 = 6;
+    let request_type_resend: u8 = 7;
+    let request_type_hello: u8 = 8;
+    let request_type_register: u8 = 9;
+    let request_type_login: u8 = 10;
+    let request_type_heartbeat: u8 = 11;
+    let request_type_leave: u8 = 12;
+    let request_type_join: u8 = 13;
+    let request_type_subscribe: u8 = 14;
+    let request_type_ack: u8 = 15;
+    let request_type_mail: u8 = 16;
+    let request_type_mail_end: u8 = 17;
+    let request_type_mail_ack: u8 = 18;
+    let request_type_relay: u8 = 19;
+    let request_type_group: u8 = 20;
+    let request_type_encoded_ack: u8 = 21;
+    let request_type_group_mail: u8 = 22;
 
     println!(
         "Client {} listening on IP address {}",
         client_num, client_ip
     );
 
-    // directory of service is a hashset of IPv4 addresses
-    let mut directory_of_service: Hashset<String> = Hashset::new();
+    // say hello to every server first, once we have their keys the login is encrypted
+    let servers = [server_1_socket, server_2_socket, server_3_socket];
+    // peers may share an ip, the servers do not, the first key they answer with stays theirs
+    for server in servers {
+        let ip = server.parse::<SocketAddr>().unwrap().ip();
+        keyring.lock().unwrap().pin(ip);
+    }
+    let hello = ImageFragment {
+        fragment: Vec::new(),
+        request_type: request_type_hello,
+        ..Default::default()
+    };
+    let encoded = serde_json::to_string(&hello).unwrap();
+    for server in servers {
+        sending_socket
+            .send_to(encoded.as_bytes(), server)
+            .expect("Failed to send data to server");
+    }
+    // a server that is down never answers
+    recieving_socket
+        .set_read_timeout(Some(Duration::from_secs(2)))
+        .unwrap();
+    let mut buffer = [0; 65535];
+    while recieving_socket.recv_from(&mut buffer).is_ok() {}
+
+    // every request after the login says it is from us with this
+    let session = identity::to_hex(&rand::random::<[u8; 16]>());
+    // log in or register until the servers accept it
+    let username = loop {
+        println!("1. to log in.");
+        println!("2. to register.");
+        let mut choice = String::new();
+        std::io::stdin()
+            .read_line(&mut choice)
+            .expect("Failed to read line");
+        let request_type = if choice.trim() == "2" {
+            request_type_register
+        } else {
+            request_type_login
+        };
+        println!("Enter your username:");
+        let mut username = String::new();
+        std::io::stdin()
+            .read_line(&mut username)
+            .expect("Failed to read line");
+        println!("Enter your password:");
+        let mut password = String::new();
+        std::io::stdin()
+            .read_line(&mut password)
+            .expect("Failed to read line");
+        let mut display_name = String::new();
+        if request_type == request_type_register {
+            println!("Enter the name others see, or nothing to use your username:");
+            std::io::stdin()
+                .read_line(&mut display_name)
+                .expect("Failed to read line");
+        }
+        let credentials = Credentials {
+            username: username.trim().to_string(),
+            password: password.trim().to_string(),
+            display_name: display_name.trim().to_string(),
+            session: session.clone(),
+        };
+        let request = ImageFragment {
+            fragment: serde_json::to_vec(&credentials).unwrap(),
+            request_type,
+            ..Default::default()
+        };
+        let encoded = serde_json::to_string(&request).unwrap();
+        for server in servers {
+            sending_socket
+                .send_to(encoded.as_bytes(), server)
+                .expect("Failed to send data to server");
+        }
+        let mut accepted = false;
+        let mut error = String::new();
+        while let Ok((amt, _)) = recieving_socket.recv_from(&mut buffer) {
+            let reply: ImageFragment =
+                match serde_json::from_str(str::from_utf8(&buffer[..amt]).unwrap()) {
+                    Ok(reply) => reply,
+                    Err(_) => continue,
+                };
+            if reply.request_type == request_type {
+                accepted = true;
+            } else if reply.request_type == request_type_error {
+                error = String::from_utf8_lossy(&reply.fragment).to_string();
+            }
+        }
+        if accepted {
+            break credentials.username;
+        }
+        println!("Could not log in: {}", error);
+    };
+    recieving_socket.set_read_timeout(None).unwrap();
+    println!("Logged in as {}", username);
+
+    // keep telling the servers we are here while we are online
+    let heartbeat_on = Arc::new(Mutex::new(true));
+    let heartbeat_on_clone = Arc::clone(&heartbeat_on);
+    let heartbeat_socket = sending_socket.try_clone().unwrap();
+    let heartbeat_session = session.clone();
+    thread::spawn(move || loop {
+        thread::sleep(Duration::from_secs(HEARTBEAT_INTERVAL));
+        if !*heartbeat_on_clone.lock().unwrap() {
+            continue;
+        }
+        let heartbeat = ImageFragment {
+            fragment: Vec::new(),
+            request_type: request_type_heartbeat,
+            session: heartbeat_session.clone(),
+            ..Default::default()
+        };
+        let encoded = serde_json::to_string(&heartbeat).unwrap();
+        for server in servers {
+            heartbeat_socket
+                .send_to(encoded.as_bytes(), server)
+                .expect("Failed to send data to server");
+        }
+    });
+
+    // changes to the directory come to the listener like messages from other clients
+    let subscribe = ImageFragment {
+        fragment: serde_json::to_vec(&listening_port).unwrap(),
+        request_type: request_type_subscribe,
+        session: session.clone(),
+        ..Default::default()
+    };
+    let encoded = serde_json::to_string(&subscribe).unwrap();
+    for server in servers {
+        sending_socket
+            .send_to(encoded.as_bytes(), server)
+            .expect("Failed to send data to server");
+    }
+
+    // directory of service has every other client, where it is and what we call it
+    let mut directory_of_service = Peers::load(&format!("peers_client_{}.json", client_num));
 
     // all encoded images vector
     let mut all_encoded_images: Vec<Vec<u8>> = Vec::new();
+    // key of every encoded image, None if the image was encoded without encryption
+    let mut image_keys: Vec<Option<Vec<u8>>> = Vec::new();
 
     // compressed image vector
     let mut all_compressed_images: Vec<Vec<u8>> = Vec::new();
@@ -150,6 +922,7 @@ fn main() {
     let directory_request = ImageFragment {
         fragment: Vec::new(),
         request_type: request_type_directory,
+        ..Default::default()
     };
     let encoded = serde_json::to_string(&directory_request).unwrap();
     sending_socket
@@ -289,6 +1062,7 @@ fn main() {
     let mut src_server;
 
     let mut image_from_server: Vec<u8> = Vec::new();
+    let mut image_key: Option<Vec<u8>> = None;
     let mut isimage = true;
     loop {
         // recieve image fragments from server, if it is a directory, print it, else, append to image_from_server
@@ -304,24 +1078,34 @@ fn main() {
 
         if request_type == request_type_directory {
             println!("Received directory from server: {}", src);
-            println!("{}", str::from_utf8(&recieved_chunk).unwrap());
             isimage = false;
 
-            // the recieved chunk has IPs separated by newlines
-            let directory = str::from_utf8(&recieved_chunk).unwrap();
-            let directory = directory.split("\n");
-            for ip in directory {
-                // make sure its not an empty string
-                if ip == "" || ip == client_ip {
-                    continue;
-                }
-                // add to directory of service with listening port
-                let ip = format!("{}:{}", ip, listening_port);
-                directory_of_service.insert(ip);
-            }
+            // the recieved chunk has every user, with its IP and the key it signs with
+            let response: DirectoryResponse =
+                serde_json::from_slice(&recieved_chunk).unwrap_or_default();
+            learn_directory(
+                &response,
+                &username,
+                listening_port,
+                &mut directory_of_service,
+                &keyring,
+            );
+            break;
+        } else if request_type == request_type_error {
+            // e.g. the image is too large for any cover the server has
+            println!(
+                "Server could not encode the image: {}",
+                str::from_utf8(&recieved_chunk).unwrap()
+            );
+            image_from_server.clear();
             break;
+        } else if request_type == request_type_key {
+            // only the owner gets the key of the image, keep it next to the image
+            image_key = Some(recieved_chunk);
         } else if request_type == request_type_image {
             if recieved_chunk == b"MINSENDEND" {
+                all_encoded_images.push(image_from_server.clone());
+                image_keys.push(image_key.take());
                 break;
             }
             image_from_server.append(&mut recieved_chunk.to_vec());
@@ -359,12 +1143,7 @@ fn main() {
     // }
 
     // print directory of service
-    println!("Directory of service:");
-    let mut num = 1;
-    for ip in directory_of_service.clone() {
-        println!("{}: {}", num, ip);
-        num += 1;
-    }
+    print_peers(&directory_of_service);
     // print number of images
 
     // let filename = format!("C:/Users/demim/OneDrive/Desktop/Uni/Fall 2023/Fundamentals of Distributed Systems/proj/Distributed_Project/encoded_image_1_client_{}.png", client_num);
@@ -385,8 +1164,21 @@ fn main() {
         let mut file_bytes = Vec::new();
         file.read_to_end(&mut file_bytes);
         all_encoded_images.push(file_bytes);
+        // these were encoded before images were encrypted
+        image_keys.push(None);
     }
 
+    // shared with the listening thread, images uploaded later are added to them
+    let all_encoded_images = Arc::new(Mutex::new(all_encoded_images));
+    let all_encoded_images_clone = Arc::clone(&all_encoded_images);
+    let image_keys = Arc::new(Mutex::new(image_keys));
+    let image_keys_clone = Arc::clone(&image_keys);
+    let all_compressed_images = Arc::new(Mutex::new(all_compressed_images));
+    let all_compressed_images_clone = Arc::clone(&all_compressed_images);
+    // the listening thread needs it to know who it is talking to
+    let directory_of_service = Arc::new(Mutex::new(directory_of_service));
+    let directory_of_service_clone = Arc::clone(&directory_of_service);
+
     // vector of image path and number of views recieved
     // (image path, views, image number, who sent it)
     // let mut all_images_recieved: Vec<(String, i32, i32, String)> = Vec::new();
@@ -395,10 +1187,26 @@ fn main() {
     let all_images_recieved_clone = Arc::clone(&all_images_recieved);
 
     // vector of pairs that has image id and destination ip
-    let mut all_images_sent: Arc<Mutex<Vec<(i32, String)>>> = Arc::new(Mutex::new(Vec::new()));
+    // (image number, who it was sent to, if it was taken back)
+    let mut all_images_sent: Arc<Mutex<Vec<(i32, String, bool)>>> =
+        Arc::new(Mutex::new(Vec::new()));
     let all_images_sent_clone = Arc::clone(&all_images_sent);
-    let mut offline_clients: Arc<Mutex<Vec<String>>> = Arc::new(Mutex::new(Vec::new()));
-    let offline_clients_clone = Arc::clone(&offline_clients);
+
+    // the owner keeps the real number of views of every image it sent,
+    // recipients have to ask for every single view
+    let view_ledger: Arc<Mutex<ViewLedger>> = Arc::new(Mutex::new(ViewLedger::new()));
+    let view_ledger_clone = Arc::clone(&view_ledger);
+    let audit_log = AuditLog::new(&format!("view_audit_client_{}.log", client_num));
+    // requests for more views wait here until we answer them
+    let inbox: Arc<Mutex<Inbox>> = Arc::new(Mutex::new(Inbox::load(&format!(
+        "inbox_client_{}.json",
+        client_num
+    ))));
+    let inbox_clone = Arc::clone(&inbox);
+    let identity_clone = Arc::clone(&identity);
+    let keyring_clone = Arc::clone(&keyring);
+    let username_clone = username.clone();
+    let session_clone = session.clone();
 
     //////////////////////////////////////////////////////////////////
 
@@ -406,15 +1214,31 @@ fn main() {
     let (tx, rx): (Sender<String>, Receiver<String>) = mpsc::channel();
     let tx_clone = mpsc::Sender::clone(&tx);
 
+    // peers that can not be reached directly get everything through the servers
+    let client_send_socket =
+        PeerLink::new(client_send_socket, &servers, request_type_relay, &session);
     let client_listen_copy = client_listen_socket.try_clone().unwrap();
     let client_send_copy = client_send_socket.try_clone().unwrap();
     let server_send_copy = sending_socket.try_clone().unwrap();
     let server_listen_copy = recieving_socket.try_clone().unwrap();
     let mut reconstructed_image_bytes: Vec<u8> = Vec::new();
-
-    let mut img_counter: u16 = 1;
+    // encrypted images we were sent, by the name shown in all_images_recieved.
+    // they stay in memory and are only written out when exported
+    let mut received_encoded_images: HashMap<String, Vec<u8>> = HashMap::new();
+    // when we first viewed every image, for images that expire some time after that
+    let mut first_views: HashMap<String, u64> = HashMap::new();
+    // views of unencrypted images that may still be resent once
+    let mut view_resends: Hashset<(i32, String)> = Hashset::new();
+    // when the last view change we took for every image was signed, by owner and image
+    let mut latest_updates: HashMap<(String, i32), u64> = HashMap::new();
+
+    // our number for the next image we grant, images left with the servers are granted too
+    let img_counter: Arc<Mutex<u16>> = Arc::new(Mutex::new(1));
+    let img_counter_clone = Arc::clone(&img_counter);
     let mut go_to_id_4 = false;
     let mut compressed_images_recieved: Vec<Vec<u8>> = Vec::new();
+    // fragments of an image the servers kept for us
+    let mut mail_bytes: Vec<u8> = Vec::new();
 
     thread::spawn(move || {
         loop {
@@ -430,13 +1254,42 @@ fn main() {
             let mut name = String::new();
             let mut is_sample;
             let mut sample_num;
+            let mut checksum = Vec::new();
+            let mut signature = Vec::new();
+            let mut revoke = false;
+            let mut issued = 0;
+            // images are granted to and owned by users, src is only where they are now
+            let mut user = String::new();
+            // only the servers tell us about the directory and the updates they kept for us
+            let mut from_server = false;
 
             if go_to_id_4 == false {
-                let (amt, src1) = client_listen_copy
-                    .recv_from(&mut buffer)
+                let (amt, src1, public_key) = client_listen_copy
+                    .recv_from_key(&mut buffer)
                     .expect("Didn't receive data");
                 encoded = str::from_utf8(&buffer[..amt]).unwrap();
                 message = serde_json::from_str(encoded).unwrap();
+                from_server = is_server(&servers, src1);
+                src = format!("{}:{}", src1.ip(), listening_port);
+                user = username_of(
+                    &directory_of_service_clone.lock().unwrap(),
+                    &public_key,
+                    &src,
+                );
+                if let Some((from, inner)) = relayed_message(&message).filter(|_| from_server) {
+                    // a peer that can not reach us sent it through the servers,
+                    // so it only gets our answer that way too
+                    src = endpoint_of(&directory_of_service_clone.lock().unwrap(), &from);
+                    client_send_copy.relay(&src, &from);
+                    user = from;
+                    from_server = false;
+                    message = inner;
+                } else if !from_server {
+                    if unreachable {
+                        continue;
+                    }
+                    client_send_copy.heard(&src);
+                }
                 msg = message.message;
                 id = message.id;
                 image_fragment = message.image_fragment;
@@ -444,14 +1297,152 @@ fn main() {
                 name = message.name;
                 is_sample = message.is_sample;
                 sample_num = message.sample_num;
-                src = src1.ip().to_string();
-                src = format!("{}:{}", src, listening_port);
+                checksum = message.checksum;
+                signature = message.signature;
+                revoke = message.revoke;
+                issued = message.issued;
             }
 
+            if id == 10 && from_server {
+                // a view change the owner sent while we were offline, tell every server we have it
+                // even if it is not signed by the owner, it is sent again until we do.
+                // the message is the owner, its images are numbered by it
+                let ack = ImageFragment {
+                    fragment: serde_json::to_vec(&(&msg, &name, views)).unwrap(),
+                    request_type: request_type_ack,
+                    session: session_clone.clone(),
+                    ..Default::default()
+                };
+                let encoded = serde_json::to_string(&ack).unwrap();
+                for server in servers {
+                    server_send_copy
+                        .send_to(encoded.as_bytes(), server)
+                        .expect("Failed to send data to server");
+                }
+                let image = match name.parse::<i32>() {
+                    Ok(image) => image,
+                    Err(_) => continue,
+                };
+                let mut all_images_recieved = all_images_recieved_clone.lock().unwrap();
+                for recieved in all_images_recieved.iter_mut() {
+                    if recieved.2 != image || recieved.3 != msg {
+                        continue;
+                    }
+                    if !signed_by_owner(
+                        &directory_of_service_clone.lock().unwrap(),
+                        &recieved.3,
+                        &ViewChange {
+                            image: &name,
+                            recipient: &username_clone,
+                            views,
+                            revoke,
+                            issued,
+                            signature: &signature,
+                        },
+                    ) {
+                        println!("Rejected view change of {} not signed by its owner", image);
+                        continue;
+                    }
+                    if !is_newer(&mut latest_updates, &recieved.3, image, issued) {
+                        println!("Rejected an old view change of {}", image);
+                        continue;
+                    }
+                    if revoke {
+                        // the owner took it back, what we were sent of it goes too
+                        println!("{} took back image {}", recieved.3, image);
+                        received_encoded_images.remove(&recieved.0);
+                        recieved.1 = 0;
+                        break;
+                    }
+                    recieved.1 = views;
+                    println!("Changed views of image: {}", image);
+                    println!("New views: {}", views);
+                    break;
+                }
+                continue;
+            }
+            if id == 15 && from_server {
+                mail_bytes.append(&mut image_fragment.clone());
+                continue;
+            }
+            if id == 16 && from_server {
+                // the last fragment of an image the owner left with the servers for us
+                let bytes = mem::take(&mut mail_bytes);
+                if !integrity::verify(&bytes, &checksum) {
+                    println!(
+                        "Image {} from {} arrived corrupted, the servers send it again",
+                        name, msg
+                    );
+                    continue;
+                }
+                let filename = format!("image_{}_from_{}", name, msg);
+                let mut all_images_recieved = all_images_recieved_clone.lock().unwrap();
+                // it is sent again if our ack was lost
+                if !all_images_recieved
+                    .iter()
+                    .any(|recieved| recieved.0 == filename)
+                {
+                    println!("Got image {} from {} through the servers", name, msg);
+                    received_encoded_images.insert(filename.clone(), bytes);
+                    let image = name.parse::<i32>().unwrap_or_default();
+                    all_images_recieved.push((filename, views, image, msg.clone()));
+                }
+                let ack = ImageFragment {
+                    fragment: serde_json::to_vec(&(&msg, name.parse::<i32>().unwrap_or_default()))
+                        .unwrap(),
+                    request_type: request_type_mail_ack,
+                    session: session_clone.clone(),
+                    ..Default::default()
+                };
+                let encoded = serde_json::to_string(&ack).unwrap();
+                for server in servers {
+                    server_send_copy
+                        .send_to(encoded.as_bytes(), server)
+                        .expect("Failed to send data to server");
+                }
+                continue;
+            }
+            if id == 14 {
+                // a user joined, left or changed
+                let entry: UserEntry = match serde_json::from_str(&msg) {
+                    Ok(entry) if from_server => entry,
+                    _ => continue,
+                };
+                println!(
+                    "{} ({}) is now {}",
+                    entry.display_name,
+                    entry.username,
+                    if entry.online { "online" } else { "offline" }
+                );
+                let online = entry.online.then(|| entry.username.clone());
+                learn_directory(
+                    &DirectoryResponse { users: vec![entry] },
+                    &username_clone,
+                    listening_port,
+                    &mut directory_of_service_clone.lock().unwrap(),
+                    &keyring_clone,
+                );
+                // answers to view requests that waited for the user
+                if let Some(online) = online {
+                    let mut inbox = inbox_clone.lock().unwrap();
+                    for decision in inbox.undelivered(&online) {
+                        send_decision(
+                            &client_send_copy,
+                            &directory_of_service_clone.lock().unwrap(),
+                            &mut inbox,
+                            &view_ledger_clone,
+                            &identity_clone,
+                            decision,
+                        );
+                    }
+                }
+                continue;
+            }
             if id == 1 {
                 // this is the first message. send the number of images.
                 // send the compressed images to the requesting client
-                let num_images = all_encoded_images.len().to_string();
+                let num_images = all_encoded_images_clone.lock().unwrap().len().to_string();
+                let all_compressed_images = all_compressed_images_clone.lock().unwrap().clone();
                 println!("Sending compressed images to client");
                 for i in 0..all_compressed_images.len() {
                     // send the struct to the client
@@ -466,6 +1457,7 @@ fn main() {
                             name: "".to_string(),
                             is_sample: true,
                             sample_num: i as u8,
+                            ..Default::default()
                         };
                     } else {
                         image_fragment = MessageType {
@@ -476,6 +1468,7 @@ fn main() {
                             name: "".to_string(),
                             is_sample: true,
                             sample_num: i as u8,
+                            ..Default::default()
                         };
                     }
                     let encoded = serde_json::to_string(&image_fragment).unwrap();
@@ -499,17 +1492,8 @@ fn main() {
                 compressed_images_recieved.push(compressed_image.clone());
 
                 // open both compressed images and ask the user which one they want to request.
-                let mut num = 1;
                 for image in compressed_images_recieved.clone() {
-                    let path = format!(
-                        "compressed_image_{}_client_{}_{}.png",
-                        num, client_num, name
-                    );
-                    let mut file = File::create(path.clone()).unwrap();
-                    file.write_all(&image);
-                    open_image(&path);
-                    delete_image(&path);
-                    num += 1;
+                    open_image(&image);
                 }
                 // clear the compressed images recieved vector
                 compressed_images_recieved.clear();
@@ -530,6 +1514,7 @@ fn main() {
                     name: "".to_string(),
                     is_sample: false,
                     sample_num: 0,
+                    ..Default::default()
                 };
                 let encoded = serde_json::to_string(&message).unwrap();
                 println!("Sending to src: {}", src);
@@ -540,79 +1525,84 @@ fn main() {
                 println!("Sent image number to requesting client");
             }
             if id == 3 {
-                // this is the third message. recieves the image number and sends the image to the requesting client.
-                // recieve the image number from the requesting client
+                // this is the third message. recieves the image number and grants the image to the requesting client.
+                // an encrypted image is sent right away since it is useless without its key,
+                // an unencrypted one is only sent when the requesting client asks to view it (id 11)
                 let image_to_send = msg.parse::<usize>().unwrap();
-                // send the image to the requesting client
-                let encoded_image = all_encoded_images[image_to_send - 1].clone();
-                println!("Sending image to requesting client");
-                let mut encoded_image_chunks = Vec::new();
-                for chunk in encoded_image.chunks(1024) {
-                    encoded_image_chunks.push(chunk);
-                }
-                // for every chunk make an instance of messageType
-                for j in 0..encoded_image_chunks.len() {
-                    // send the struct to the client
-                    let image_fragment = MessageType {
-                        message: String::new(),
-                        id: 4,
-                        image_fragment: encoded_image_chunks[j].to_vec(),
-                        views: 0,
-                        name: "".to_string(),
-                        is_sample: false,
-                        sample_num: 0,
-                    };
-                    let encoded = serde_json::to_string(&image_fragment).unwrap();
-                    // send to the requesting client
-                    client_send_copy
-                        .send_to(encoded.as_bytes(), &src)
-                        .expect("Failed to send data to server");
-                    if j % 10 == 0 && j != 0 {
-                        // sleep for 1 second
-                        thread::sleep(Duration::from_millis(50));
-                    }
+                let mut transfer_checksum = Vec::new();
+                if image_keys_clone.lock().unwrap()[image_to_send - 1].is_some() {
+                    let encoded_image =
+                        all_encoded_images_clone.lock().unwrap()[image_to_send - 1].clone();
+                    println!("Sending encrypted image to requesting client");
+                    send_chunks(&client_send_copy, &src, &encoded_image, 4);
+                    transfer_checksum = integrity::checksum(&encoded_image);
                 }
-                // send end to the requesting client
+                let views = 3;
+                let mut img_counter = img_counter_clone.lock().unwrap();
+                view_ledger_clone.lock().unwrap().grant(
+                    *img_counter as i32,
+                    &user,
+                    image_to_send - 1,
+                    views,
+                    transfer_checksum.clone(),
+                );
+                println!("Granting image to requesting client");
                 let end_message = "MINSENDEND";
                 let final_message = MessageType {
                     message: end_message.to_string(),
                     id: 4,
                     image_fragment: Vec::new(),
-                    views: 3,
+                    views: views,
                     name: img_counter.to_string(),
                     is_sample: false,
                     sample_num: 0,
+                    checksum: transfer_checksum,
+                    ..Default::default()
                 };
                 let encoded = serde_json::to_string(&final_message).unwrap();
                 client_send_copy
                     .send_to(encoded.as_bytes(), &src)
                     .expect("Failed to send data to server");
                 // add to all images sent
-                let image_info = (img_counter as i32, src.clone());
+                let image_info = (*img_counter as i32, user.clone(), false);
                 let mut all_images_sent = all_images_sent_clone.lock().unwrap();
                 all_images_sent.push(image_info);
-                img_counter += 1;
+                *img_counter += 1;
             }
             if id == 4 || go_to_id_4 == true {
-                // reconstruct the image from the chunks
-                println!("Receiving image from client: {}", src);
+                // reconstruct the image from the chunks, it is either encrypted or
+                // not sent at all until we view it
                 reconstructed_image_bytes.append(&mut image_fragment.clone());
                 if msg == "MINSENDEND" || go_to_id_4 == true {
-                    if (go_to_id_4 == false) {
-                        println!("Reconstructed image from client: {}", src);
-                        // write the image to a file
-                        let filename = format!(
-                            "reconstructed_image_client_{}_{}_{}.png",
-                            client_num, name, src
-                        );
-                        let mut file = File::create(filename.clone()).unwrap();
-                        file.write_all(&reconstructed_image_bytes);
-                        println!("Reconstructed image from client: {}", src);
-                        // clear the reconstructed image bytes vector
+                    if (go_to_id_4 == false)
+                        && !integrity::verify(&reconstructed_image_bytes, &checksum)
+                    {
+                        // drop it and ask the owner for the same image again
+                        println!("Image {} from client {} arrived corrupted", name, src);
                         reconstructed_image_bytes.clear();
+                        let message = MessageType {
+                            message: "4".to_string(),
+                            id: 13,
+                            name: name.clone(),
+                            ..Default::default()
+                        };
+                        let encoded = serde_json::to_string(&message).unwrap();
+                        client_send_copy
+                            .send_to(encoded.as_bytes(), &src)
+                            .expect("Failed to send data to client");
+                        continue;
+                    }
+                    if (go_to_id_4 == false) {
+                        println!("Granted image {} from client: {}", name, src);
+                        let filename = format!("image_{}_from_{}", name, user);
+                        if !reconstructed_image_bytes.is_empty() {
+                            received_encoded_images
+                                .insert(filename.clone(), reconstructed_image_bytes.clone());
+                            reconstructed_image_bytes.clear();
+                        }
                         // add to all images recieved
                         let image_info =
-                            (filename, views, name.parse::<i32>().unwrap(), src.clone());
+                            (filename, views, name.parse::<i32>().unwrap(), user.clone());
                         let mut all_images_recieved = all_images_recieved_clone.lock().unwrap();
                         all_images_recieved.push(image_info);
                     }
@@ -624,6 +1614,7 @@ fn main() {
                         println!("2. to request another image.");
                         println!("3. to renew views of an image.");
                         println!("4. to exit.");
+                        println!("5. to export an encrypted image.");
                         let mut choice = String::new();
                         std::io::stdin()
                             .read_line(&mut choice)
@@ -653,27 +1644,256 @@ fn main() {
                                 if (all_images_recieved[image_to_view].1) == 0 {
                                     println!("You do not have access to this image");
                                 } else {
-                                    // decode the image
-                                    let encoded_image =
-                                        file_as_image_buffer(image_to_view1.clone());
-                                    let dec = Decoder::new(encoded_image);
-                                    let out_buffer = dec.decode_alpha();
-                                    let clean_buffer: Vec<u8> =
-                                        out_buffer.into_iter().filter(|b| *b != 0xff_u8).collect();
-                                    let message = bytes_to_str(clean_buffer.as_slice());
-                                    let decoded_image = base64::decode(message).unwrap();
-                                    let path = format!(
-                                        "decoded_image_{}_client_{}_finalview.png",
-                                        image_to_view, client_num
+                                    // ask the owner for this view, it checks its own count and
+                                    // only then sends the encoded image
+                                    let owner = endpoint_of(
+                                        &directory_of_service_clone.lock().unwrap(),
+                                        &all_images_recieved[image_to_view].3,
                                     );
-                                    let mut file = File::create(path.clone()).unwrap();
-                                    file.write_all(&decoded_image);
-                                    open_image(&path);
-                                    delete_image(&path);
-                                    // decrement the views of the image
-                                    let mut views = all_images_recieved[image_to_view].1;
-                                    views -= 1;
-                                    all_images_recieved[image_to_view].1 = views;
+                                    let message = MessageType {
+                                        message: "VIEW".to_string(),
+                                        id: 11,
+                                        image_fragment: Vec::new(),
+                                        views: 0,
+                                        name: all_images_recieved[image_to_view].2.to_string(),
+                                        is_sample: false,
+                                        sample_num: 0,
+                                        ..Default::default()
+                                    };
+                                    let encoded = serde_json::to_string(&message).unwrap();
+                                    client_send_copy
+                                        .request(
+                                            encoded.as_bytes(),
+                                            &owner,
+                                            &all_images_recieved[image_to_view].3,
+                                        )
+                                        .expect("Failed to send data to client");
+
+                                    let mut encoded_image_bytes: Vec<u8> = Vec::new();
+                                    let mut key: Vec<u8> = Vec::new();
+                                    let mut granted = false;
+                                    let mut remaining = 0;
+                                    let mut grant_signature: Vec<u8> = Vec::new();
+                                    // an owner that answers neither directly nor through the
+                                    // servers is given up on
+                                    client_listen_copy
+                                        .set_read_timeout(Some(Duration::from_secs(
+                                            3 * DIRECT_TIMEOUT,
+                                        )))
+                                        .unwrap();
+                                    let mut answered = true;
+                                    loop {
+                                        let mut buffer = [0; 65535];
+                                        let (amt, reply_src) =
+                                            match client_listen_copy.recv_from(&mut buffer) {
+                                                Ok(received) => received,
+                                                Err(_) => {
+                                                    answered = false;
+                                                    break;
+                                                }
+                                            };
+                                        let reply: MessageType = match serde_json::from_str(
+                                            str::from_utf8(&buffer[..amt]).unwrap(),
+                                        ) {
+                                            Ok(reply) => reply,
+                                            Err(_) => continue,
+                                        };
+                                        let reply = if is_server(&servers, reply_src) {
+                                            match relayed_message(&reply) {
+                                                Some((_, reply)) => reply,
+                                                None => continue,
+                                            }
+                                        } else if unreachable {
+                                            continue;
+                                        } else {
+                                            client_send_copy.heard(&format!(
+                                                "{}:{}",
+                                                reply_src.ip(),
+                                                listening_port
+                                            ));
+                                            reply
+                                        };
+                                        if reply.id != 12 {
+                                            continue;
+                                        }
+                                        if reply.message == "MINSENDEND"
+                                            && !integrity::verify(
+                                                &encoded_image_bytes,
+                                                &reply.checksum,
+                                            )
+                                        {
+                                            // the view was already counted, the owner resends it once
+                                            println!("Image arrived corrupted, asking again");
+                                            encoded_image_bytes.clear();
+                                            let message = MessageType {
+                                                message: "12".to_string(),
+                                                id: 13,
+                                                name: reply.name.clone(),
+                                                ..Default::default()
+                                            };
+                                            let encoded = serde_json::to_string(&message).unwrap();
+                                            client_send_copy
+                                                .send_to(encoded.as_bytes(), &owner)
+                                                .expect("Failed to send data to client");
+                                            continue;
+                                        }
+                                        if reply.message == "MINSENDEND" {
+                                            granted = true;
+                                            remaining = reply.views;
+                                            key = reply.image_fragment;
+                                            grant_signature = reply.signature;
+                                            break;
+                                        }
+                                        if reply.message == "DENIED" {
+                                            remaining = reply.views;
+                                            break;
+                                        }
+                                        encoded_image_bytes
+                                            .append(&mut reply.image_fragment.clone());
+                                    }
+                                    client_listen_copy.set_read_timeout(None).unwrap();
+                                    if !answered {
+                                        println!(
+                                            "{} did not answer, try again later",
+                                            all_images_recieved[image_to_view].3
+                                        );
+                                        continue;
+                                    }
+                                    // the owner's count is the one that matters
+                                    all_images_recieved[image_to_view].1 = remaining;
+                                    if !granted {
+                                        println!("The owner denied viewing {}", image_to_view1);
+                                        continue;
+                                    }
+
+                                    // decode the image, an encrypted image is already stored here
+                                    // and the owner only sent its key
+                                    if encoded_image_bytes.is_empty() {
+                                        match received_encoded_images.get(&image_to_view1) {
+                                            Some(stored) => encoded_image_bytes = stored.clone(),
+                                            None => {
+                                                println!("{} was never received", image_to_view1);
+                                                continue;
+                                            }
+                                        }
+                                    } else if !key.is_empty() {
+                                        // the owner encrypted it again, the old copy is no use
+                                        received_encoded_images.insert(
+                                            image_to_view1.clone(),
+                                            encoded_image_bytes.clone(),
+                                        );
+                                    }
+                                    let encoded_image =
+                                        image::load_from_memory(&encoded_image_bytes);
+                                    let encoded_image = match encoded_image {
+                                        Ok(encoded_image) => encoded_image.to_rgba8(),
+                                        Err(e) => {
+                                            println!("Could not open {}: {}", image_to_view1, e);
+                                            continue;
+                                        }
+                                    };
+                                    // the stego header says which codec was used
+                                    let payload = match stego::decode(&encoded_image) {
+                                        Some(payload) => payload,
+                                        None => {
+                                            println!("No hidden image found in {}", image_to_view1);
+                                            continue;
+                                        }
+                                    };
+                                    // images with metadata carry their owner's key,
+                                    // the metadata and the grant both have to be signed with it
+                                    let (image_metadata, payload) = metadata::unpack(&payload);
+                                    if let Some(image_metadata) = &image_metadata {
+                                        if !image_metadata.verify() {
+                                            println!(
+                                                "The metadata of {} is not signed by its owner",
+                                                image_to_view1
+                                            );
+                                            continue;
+                                        }
+                                        let grant = metadata::grant_bytes(
+                                            all_images_recieved[image_to_view].2,
+                                            &username_clone,
+                                            remaining,
+                                            &key,
+                                        );
+                                        if !key.is_empty()
+                                            && !identity::verify(
+                                                &image_metadata.owner,
+                                                &grant,
+                                                &grant_signature,
+                                            )
+                                        {
+                                            println!(
+                                                "The view grant for {} is not signed by its owner",
+                                                image_to_view1
+                                            );
+                                            continue;
+                                        }
+                                        println!(
+                                            "{} is image {} of owner {}, created at {}",
+                                            image_to_view1,
+                                            image_metadata.image_id,
+                                            identity::fingerprint(&image_metadata.owner),
+                                            image_metadata.created
+                                        );
+                                        // the owner's policy is signed with the metadata,
+                                        // an expired image is deleted
+                                        let first_view = *first_views
+                                            .entry(image_to_view1.clone())
+                                            .or_insert_with(metadata::now);
+                                        if let Some(reason) =
+                                            image_metadata.policy.expired(first_view)
+                                        {
+                                            println!("{} is deleted, {}", image_to_view1, reason);
+                                            received_encoded_images.remove(&image_to_view1);
+                                            all_images_recieved[image_to_view].1 = 0;
+                                            continue;
+                                        }
+                                    }
+                                    let decoded_image = if key.is_empty() {
+                                        payload.to_vec()
+                                    } else {
+                                        match crypto::open(&key, payload) {
+                                            Some(decoded_image) => decoded_image,
+                                            None => {
+                                                println!("Could not decrypt {}", image_to_view1);
+                                                continue;
+                                            }
+                                        }
+                                    };
+                                    if let Some(image_metadata) = &image_metadata {
+                                        if !integrity::verify(
+                                            &decoded_image,
+                                            &image_metadata.content_hash,
+                                        ) {
+                                            println!(
+                                                "{} is not the image its owner signed",
+                                                image_to_view1
+                                            );
+                                            continue;
+                                        }
+                                    }
+                                    open_image(&decoded_image);
+                                    // the owner keeps count of what was really viewed
+                                    let viewed = MessageType {
+                                        message: "VIEWED".to_string(),
+                                        id: 18,
+                                        name: all_images_recieved[image_to_view].2.to_string(),
+                                        ..Default::default()
+                                    };
+                                    let encoded = serde_json::to_string(&viewed).unwrap();
+                                    client_send_copy
+                                        .send_to(encoded.as_bytes(), &owner)
+                                        .expect("Failed to send data to client");
+                                    if image_metadata.is_some_and(|m| m.policy.view_once) {
+                                        println!(
+                                            "{} could be viewed once, it is deleted",
+                                            image_to_view1
+                                        );
+                                        received_encoded_images.remove(&image_to_view1);
+                                        all_images_recieved[image_to_view].1 = 0;
+                                    }
                                 }
                             }
                             2 => {
@@ -701,10 +1921,15 @@ fn main() {
                                     image_to_add_views.trim().parse::<usize>().unwrap();
                                 let image_to_add_views1 = image_to_add_views1 - 1;
                                 let image_to_add_views = all_images_recieved[image_to_add_views1].2;
-                                let client_to_send_to_ip = all_images_recieved[image_to_add_views1]
-                                    .3
-                                    .parse::<SocketAddr>()
-                                    .unwrap();
+                                let owner = all_images_recieved[image_to_add_views1].3.clone();
+                                let endpoint = endpoint_of(
+                                    &directory_of_service_clone.lock().unwrap(),
+                                    &owner,
+                                );
+                                // an owner we have no address for is only reached through the servers
+                                if endpoint.parse::<SocketAddr>().is_err() {
+                                    client_send_copy.relay(&endpoint, &owner);
+                                }
 
                                 let message = MessageType {
                                     message: "".to_string(),
@@ -714,19 +1939,51 @@ fn main() {
                                     name: image_to_add_views.to_string(),
                                     is_sample: false,
                                     sample_num: 0,
+                                    ..Default::default()
                                 };
                                 let encoded = serde_json::to_string(&message).unwrap();
-                                // this is sent to the address of the client that sent the image
-                                client_send_copy
-                                    .send_to(encoded.as_bytes(), client_to_send_to_ip)
-                                    .expect("Failed to send data to server");
-
+                                // this is sent to the owner of the image, its answer is taken
+                                // whenever it comes while the menu is back
+                                if let Err(e) =
+                                    client_send_copy.request(encoded.as_bytes(), &endpoint, &owner)
+                                {
+                                    println!("Could not ask {} for more views: {}", owner, e);
+                                }
+                                tx_clone.send("renew views".to_string()).unwrap();
                                 break;
                             }
                             4 => {
                                 // exit
                                 break;
                             }
+                            5 => {
+                                // write an image we hold to disk, it stays hidden and encrypted
+                                for i in 0..all_images_recieved.len() {
+                                    println!("{}. {}", i + 1, all_images_recieved[i].0);
+                                }
+                                println!("Enter the number of the image you want to export:");
+                                let mut image_to_export = String::new();
+                                std::io::stdin()
+                                    .read_line(&mut image_to_export)
+                                    .expect("Failed to read line");
+                                let image_to_export =
+                                    image_to_export.trim().parse::<usize>().unwrap() - 1;
+                                let filename = all_images_recieved[image_to_export].0.clone();
+                                match received_encoded_images.get(&filename) {
+                                    Some(encoded_image) => {
+                                        let path = format!("{}.png", filename.replace(':', "_"));
+                                        let mut file = File::create(path.clone()).unwrap();
+                                        file.write_all(encoded_image).unwrap();
+                                        println!("Exported {} to {}", filename, path);
+                                    }
+                                    None => {
+                                        println!(
+                                            "The owner only sends {} when it is viewed",
+                                            filename
+                                        )
+                                    }
+                                }
+                            }
                             _ => {
                                 println!("Invalid choice");
                             }
@@ -740,6 +1997,38 @@ fn main() {
                 let mut all_images_recieved = all_images_recieved_clone.lock().unwrap();
                 for i in 0..all_images_recieved.len() {
                     if all_images_recieved[i].2 == image_to_change_views {
+                        if !signed_by_owner(
+                            &directory_of_service_clone.lock().unwrap(),
+                            &all_images_recieved[i].3,
+                            &ViewChange {
+                                image: &name,
+                                recipient: &username_clone,
+                                views: new_views,
+                                revoke,
+                                issued,
+                                signature: &signature,
+                            },
+                        ) {
+                            println!("Rejected view change of {} not signed by its owner", name);
+                            continue;
+                        }
+                        if !is_newer(
+                            &mut latest_updates,
+                            &all_images_recieved[i].3,
+                            image_to_change_views,
+                            issued,
+                        ) {
+                            println!("Rejected an old view change of {}", name);
+                            continue;
+                        }
+                        if revoke {
+                            // the owner took it back, what we were sent of it goes too
+                            println!(
+                                "{} took back image {}",
+                                all_images_recieved[i].3, image_to_change_views
+                            );
+                            received_encoded_images.remove(&all_images_recieved[i].0);
+                        }
                         mem::replace(&mut all_images_recieved[i].1, new_views);
                         println!("Changed views of image: {}", image_to_change_views);
                         println!("New views: {}", all_images_recieved[i].1);
@@ -750,52 +2039,27 @@ fn main() {
                 }
             }
             if id == 6 {
-                println!("Client {} wants to add views to image {}", src, name);
-                println!("1. to approve.");
-                println!("2. to decline.");
-
-                let mut choice = String::new();
-                std::io::stdin()
-                    .read_line(&mut choice)
-                    .expect("Failed to read line");
-                let choice = choice.trim().parse::<u8>().unwrap();
-
-                let message: MessageType;
-                if choice == 1 {
-                    // approve
-                    // send a yes message to the sending client
-                    message = MessageType {
-                        message: "yes".to_string(),
-                        id: 7,
-                        image_fragment: Vec::new(),
-                        views: 3,
-                        name: name.clone(),
-                        is_sample: false,
-                        sample_num: 0,
-                    };
-                } else {
-                    // decline
-                    message = MessageType {
-                        message: "no".to_string(),
-                        id: 7,
-                        image_fragment: Vec::new(),
-                        views: 0,
-                        name: name.clone(),
-                        is_sample: false,
-                        sample_num: 0,
-                    };
+                // a recipient wants more views, a rule may answer it, otherwise it waits for us
+                let image = match name.parse::<i32>() {
+                    Ok(image) => image,
+                    Err(_) => continue,
+                };
+                let mut inbox = inbox_clone.lock().unwrap();
+                match inbox.request(&user, image) {
+                    Some(decision) => send_decision(
+                        &client_send_copy,
+                        &directory_of_service_clone.lock().unwrap(),
+                        &mut inbox,
+                        &view_ledger_clone,
+                        &identity_clone,
+                        decision,
+                    ),
+                    None => println!(
+                        "{} asks for more views of image {}, answer it with 3",
+                        user, image
+                    ),
                 }
-
-                tx_clone.send("change views".to_string()).unwrap();
-
-                println!("Sending message to client: {}", src);
-                println!("Message: {}", message.message);
-
-                let encoded = serde_json::to_string(&message).unwrap();
-                // this is sent to the address of the client that sent the image
-                client_send_copy
-                    .send_to(encoded.as_bytes(), &src)
-                    .expect("Failed to send data to server");
+                continue;
             }
             if id == 7 {
                 if msg == "yes" {
@@ -805,6 +2069,30 @@ fn main() {
                     let mut all_images_recieved = all_images_recieved_clone.lock().unwrap();
                     for i in 0..all_images_recieved.len() {
                         if all_images_recieved[i].2 == image_to_change_views {
+                            if !signed_by_owner(
+                                &directory_of_service_clone.lock().unwrap(),
+                                &all_images_recieved[i].3,
+                                &ViewChange {
+                                    image: &name,
+                                    recipient: &username_clone,
+                                    views: new_views,
+                                    revoke: false,
+                                    issued,
+                                    signature: &signature,
+                                },
+                            ) {
+                                println!("Rejected views of {} not signed by its owner", name);
+                                continue;
+                            }
+                            if !is_newer(
+                                &mut latest_updates,
+                                &all_images_recieved[i].3,
+                                image_to_change_views,
+                                issued,
+                            ) {
+                                println!("Rejected old views of {}", name);
+                                continue;
+                            }
                             mem::replace(&mut all_images_recieved[i].1, new_views);
                             println!("Changed views of image: {}", image_to_change_views);
                             println!("New views: {}", all_images_recieved[i].1);
@@ -815,53 +2103,169 @@ fn main() {
                 } else {
                     println!("Client {} declined to add views to image {}", src, name);
                 }
-                go_to_id_4 = true;
             }
-            if id == 8 {
-                // send to server that the src client is offline
-                let offline_message = "OFFLINE";
-                // add the offline client to the vector of offline clients
-                let mut offline_clients = offline_clients_clone.lock().unwrap();
-                offline_clients.push(src.clone());
-
-                let mut src_to_send = src.clone();
-                // remove the port number
-                src_to_send.truncate(src_to_send.len() - 5);
-                // add the port number 9999
-                src_to_send = format!("{}:{}", src_to_send, 9999);
-
-                // send to server offline message and with it the IP and PORT of client that went offline
-
-                let message = MessageType {
-                    message: src_to_send.clone(),
-                    id: 111,
-                    image_fragment: Vec::new(),
-                    views: 0,
-                    name: "".to_string(),
-                    is_sample: false,
-                    sample_num: 0,
+            if id == 18 {
+                // a recipient opened an image we gave it a view of
+                let image = match name.parse::<i32>() {
+                    Ok(image) => image,
+                    Err(_) => continue,
                 };
-
-                let encoded = serde_json::to_string(&message).unwrap();
-                // this is sent to the address of the client that sent the image
-                server_send_copy
-                    .send_to(encoded.as_bytes(), &server_1_socket)
-                    .expect("Failed to send data to server");
-                server_send_copy
-                    .send_to(encoded.as_bytes(), &server_2_socket)
-                    .expect("Failed to send data to server");
-                server_send_copy
-                    .send_to(encoded.as_bytes(), &server_3_socket)
-                    .expect("Failed to send data to server");
+                if view_ledger_clone.lock().unwrap().report(image, &user) {
+                    let remaining = view_ledger_clone
+                        .lock()
+                        .unwrap()
+                        .get(image, &user)
+                        .map_or(0, |grant| grant.remaining);
+                    audit_log.record(image, &user, "viewed", remaining);
+                }
+                continue;
             }
-            if id == 9 {
-                // remove from the offline clients vector
-                let mut offline_clients = offline_clients_clone.lock().unwrap();
-                for i in 0..offline_clients.len() {
-                    if offline_clients[i] == src {
-                        offline_clients.remove(i);
-                        println!("Client {} is back online", src);
-                        break;
+            if id == 11 {
+                // a recipient wants to view an image, use up one of its views and send the image
+                let image = name.parse::<i32>().unwrap();
+                let grant = view_ledger_clone.lock().unwrap().consume(image, &user);
+                match grant {
+                    Some(grant) if image_keys_clone.lock().unwrap()[grant.source].is_some() => {
+                        // the requesting client already has the encrypted image, only send the key.
+                        // if the image was encrypted again since, it needs the new copy first
+                        audit_log.record(image, &user, "granted", grant.remaining);
+                        let key = image_keys_clone.lock().unwrap()[grant.source]
+                            .clone()
+                            .unwrap();
+                        let encoded_image =
+                            all_encoded_images_clone.lock().unwrap()[grant.source].clone();
+                        let mut checksum = Vec::new();
+                        if grant.copy != integrity::checksum(&encoded_image) {
+                            println!("Sending new copy of image {} to client: {}", image, src);
+                            send_chunks(&client_send_copy, &src, &encoded_image, 12);
+                            checksum = integrity::checksum(&encoded_image);
+                            view_ledger_clone.lock().unwrap().sent_copy(
+                                image,
+                                &user,
+                                checksum.clone(),
+                            );
+                            view_resends.insert((image, user.clone()));
+                        }
+                        println!("Sending key of image {} to client: {}", image, src);
+                        // signed so the recipient knows the grant comes from the image's owner
+                        let signature = identity_clone.sign(&metadata::grant_bytes(
+                            image,
+                            &user,
+                            grant.remaining,
+                            &key,
+                        ));
+                        let message = MessageType {
+                            message: "MINSENDEND".to_string(),
+                            id: 12,
+                            image_fragment: key,
+                            views: grant.remaining,
+                            name: image.to_string(),
+                            is_sample: false,
+                            sample_num: 0,
+                            checksum,
+                            signature,
+                            ..Default::default()
+                        };
+                        let encoded = serde_json::to_string(&message).unwrap();
+                        client_send_copy
+                            .send_to(encoded.as_bytes(), &src)
+                            .expect("Failed to send data to client");
+                    }
+                    Some(grant) => {
+                        audit_log.record(image, &user, "granted", grant.remaining);
+                        let encoded_image =
+                            all_encoded_images_clone.lock().unwrap()[grant.source].clone();
+                        println!("Sending image {} to view to client: {}", image, src);
+                        send_chunks(&client_send_copy, &src, &encoded_image, 12);
+                        // one resend is allowed if this arrives corrupted
+                        view_resends.insert((image, user.clone()));
+                        let final_message = MessageType {
+                            message: "MINSENDEND".to_string(),
+                            id: 12,
+                            image_fragment: Vec::new(),
+                            views: grant.remaining,
+                            name: image.to_string(),
+                            is_sample: false,
+                            sample_num: 0,
+                            checksum: integrity::checksum(&encoded_image),
+                            ..Default::default()
+                        };
+                        let encoded = serde_json::to_string(&final_message).unwrap();
+                        client_send_copy
+                            .send_to(encoded.as_bytes(), &src)
+                            .expect("Failed to send data to client");
+                    }
+                    None => {
+                        audit_log.record(image, &user, "denied", 0);
+                        println!("Denied client {} from viewing image {}", src, image);
+                        let message = MessageType {
+                            message: "DENIED".to_string(),
+                            id: 12,
+                            image_fragment: Vec::new(),
+                            views: 0,
+                            name: image.to_string(),
+                            is_sample: false,
+                            sample_num: 0,
+                            ..Default::default()
+                        };
+                        let encoded = serde_json::to_string(&message).unwrap();
+                        client_send_copy
+                            .send_to(encoded.as_bytes(), &src)
+                            .expect("Failed to send data to client");
+                    }
+                }
+            }
+            if id == 13 {
+                // a transfer to the requesting client arrived corrupted, send it again.
+                // this never uses up a view, so an unencrypted image (id 12) is only
+                // resent once for every view that was granted
+                let image = name.parse::<i32>().unwrap();
+                let grant = view_ledger_clone.lock().unwrap().get(image, &user).cloned();
+                // nothing of a revoked image is sent again
+                if let Some(grant) = grant.filter(|grant| !grant.revoked) {
+                    let encrypted = image_keys_clone.lock().unwrap()[grant.source].is_some();
+                    let transfer_id = if msg == "12" { 12 } else { 4 };
+                    let allowed = if transfer_id == 4 {
+                        encrypted
+                    } else {
+                        view_resends.remove(&(image, user.clone()))
+                    };
+                    if allowed {
+                        println!("Resending image {} to client: {}", image, src);
+                        let encoded_image =
+                            all_encoded_images_clone.lock().unwrap()[grant.source].clone();
+                        send_chunks(&client_send_copy, &src, &encoded_image, transfer_id);
+                        // a new copy of an encrypted image is sent along with its key
+                        let key = match image_keys_clone.lock().unwrap()[grant.source].clone() {
+                            Some(key) if transfer_id == 12 => key,
+                            _ => Vec::new(),
+                        };
+                        let signature = if key.is_empty() {
+                            Vec::new()
+                        } else {
+                            identity_clone.sign(&metadata::grant_bytes(
+                                image,
+                                &user,
+                                grant.remaining,
+                                &key,
+                            ))
+                        };
+                        let final_message = MessageType {
+                            message: "MINSENDEND".to_string(),
+                            id: transfer_id,
+                            image_fragment: key,
+                            views: grant.remaining,
+                            name: image.to_string(),
+                            is_sample: false,
+                            sample_num: 0,
+                            checksum: integrity::checksum(&encoded_image),
+                            signature,
+                            ..Default::default()
+                        };
+                        let encoded = serde_json::to_string(&final_message).unwrap();
+                        client_send_copy
+                            .send_to(encoded.as_bytes(), &src)
+                            .expect("Failed to send data to client");
                     }
                 }
             }
@@ -884,7 +2288,7 @@ fn main() {
                 // recieve from channel
                 if skipthis == false {
                     let recieved = rx.recv().unwrap();
-                    if recieved != "request image" {
+                    if recieved != "request image" && recieved != "renew views" {
                         continue;
                     }
                 }
@@ -892,13 +2296,20 @@ fn main() {
         }
         skipthis = false;
         if go_online == true {
-            println!("5. Simulate going online.");
+            println!("5. Go back online.");
         } else {
             // do u want to send to client or change views of a sent image
             println!("1. Request from client.");
             println!("2. Change views of a sent image.");
-            println!("3. Accept remote changing of views.");
-            println!("4. Simulate going offline.");
+            println!("3. Answer requests for more views.");
+            println!("4. Go offline.");
+            println!("6. Upload a new image.");
+            println!("7. Search the directory of service.");
+            println!("8. Name a peer or make it a favorite.");
+            println!("9. Leave an image with the servers for a user.");
+            println!("10. Show the views of the images you sent.");
+            println!("11. Manage your groups.");
+            println!("12. Send an image to a group.");
         }
 
         let mut choice = String::new();
@@ -909,24 +2320,32 @@ fn main() {
 
         match choice {
             1 => {
-                println!("Enter the number of the client you want to send to:");
+                print_peers(&directory_of_service.lock().unwrap());
+                println!("Enter the id, username or nickname of the client you want to send to:");
                 let mut client_to_send_to = String::new();
                 std::io::stdin()
                     .read_line(&mut client_to_send_to)
                     .expect("Failed to read line");
-                let client_to_send_to = client_to_send_to.trim().parse::<u16>().unwrap();
-                // if the index is greater than the number of clients, loop again
 
                 // get the ip of the client to send to from the directory of service
-                let mut client_to_send_to_ip = String::new();
-                let mut num = 1;
-                for ip in directory_of_service.clone() {
-                    if num == client_to_send_to {
-                        client_to_send_to_ip = ip;
-                        break;
+                let (client_to_send_to_ip, client_to_send_to) = match directory_of_service
+                    .lock()
+                    .unwrap()
+                    .find(&client_to_send_to)
+                {
+                    Some(peer) if peer.online => (
+                        peer.endpoint.clone().unwrap_or_default(),
+                        peer.username.clone(),
+                    ),
+                    Some(peer) => {
+                        println!("{} is offline", peer.name());
+                        continue;
                     }
-                    num += 1;
-                }
+                    None => {
+                        println!("There is no peer {}", client_to_send_to.trim());
+                        continue;
+                    }
+                };
                 // send to that client "HELLO"
                 let hello_message = "HELLO";
                 let id = 1;
@@ -938,26 +2357,36 @@ fn main() {
                     name: "".to_string(),
                     is_sample: false,
                     sample_num: 0,
+                    ..Default::default()
                 };
 
                 let encoded = serde_json::to_string(&message).unwrap();
                 client_send_socket
-                    .send_to(encoded.as_bytes(), &client_to_send_to_ip)
+                    .request(
+                        encoded.as_bytes(),
+                        &client_to_send_to_ip,
+                        &client_to_send_to,
+                    )
                     .expect("Failed to send data to server");
                 message_count += 1;
             }
             2 => {
-                let all_images_sent = all_images_sent.lock().unwrap();
+                let mut all_images_sent = all_images_sent.lock().unwrap();
                 if all_images_sent.len() == 0 {
                     println!("You have not sent any images");
                     continue;
                 }
                 for i in 0..all_images_sent.len() {
                     println!(
-                        "{}. {} sent to {}",
+                        "{}. {} sent to {}{}",
                         i + 1,
                         all_images_sent[i].0,
-                        all_images_sent[i].1
+                        all_images_sent[i].1,
+                        if all_images_sent[i].2 {
+                            " (taken back)"
+                        } else {
+                            ""
+                        }
                     );
                 }
                 println!("Enter the number of the image you want to change views of:");
@@ -967,76 +2396,237 @@ fn main() {
                     .expect("Failed to read line");
                 let input_choice = image_to_change_views.trim().parse::<usize>().unwrap();
                 let image_to_change_views = input_choice - 1;
+                if all_images_sent[image_to_change_views].2 {
+                    println!("You took this image back");
+                    continue;
+                }
                 let image_to_change_views = all_images_sent[image_to_change_views].0.clone();
-                println!("Enter the new number of views:");
+                println!("Enter the new number of views, or revoke to take the image back:");
                 let mut new_views = String::new();
                 std::io::stdin()
                     .read_line(&mut new_views)
                     .expect("Failed to read line");
-                let new_views = new_views.trim().parse::<i32>().unwrap();
-                // send the number of images to the requesting client
-                // get the src of the client from the all_images_sent vector
-                let x = all_images_sent[input_choice - 1].1.clone();
-                // remove the port number and 9999
-                let mut x_clone = x.clone();
-                x_clone.truncate(x_clone.len() - 5);
-                // add the port number 9999
-                x_clone = format!("{}:{}", x_clone, 9999);
-
+                let revoke = new_views.trim() == "revoke";
+                let new_views = if revoke {
+                    0
+                } else {
+                    new_views.trim().parse::<i32>().unwrap()
+                };
+                // the owner's ledger is what decides if a view is allowed
+                if revoke {
+                    let mut view_ledger = view_ledger.lock().unwrap();
+                    view_ledger.revoke(image_to_change_views, &all_images_sent[input_choice - 1].1);
+                    all_images_sent[input_choice - 1].2 = true;
+                    // every recipient of the image had the same key, it is encrypted again so the
+                    // key the revoked one has opens nothing it is sent from now on.
+                    // the others get the new copy with their next view
+                    let source = view_ledger
+                        .get(image_to_change_views, &all_images_sent[input_choice - 1].1)
+                        .map(|grant| grant.source);
+                    if let Some(source) = source {
+                        let mut image_keys = image_keys.lock().unwrap();
+                        let mut all_encoded_images = all_encoded_images.lock().unwrap();
+                        if let Some(key) = image_keys[source].clone() {
+                            match reseal(&all_encoded_images[source], &key) {
+                                Some((encoded_image, key)) => {
+                                    all_encoded_images[source] = encoded_image;
+                                    image_keys[source] = Some(key);
+                                    println!("The image is encrypted again with a new key");
+                                }
+                                None => println!("Could not encrypt the image again"),
+                            }
+                        }
+                    }
+                } else {
+                    view_ledger.lock().unwrap().set_views(
+                        image_to_change_views,
+                        &all_images_sent[input_choice - 1].1,
+                        new_views,
+                    );
+                }
+                // the servers keep it for the recipient by its username, wherever it is
+                let recipient = all_images_sent[input_choice - 1].1.clone();
+                let issued = auth::timestamp();
                 let message = MessageType {
-                    message: x_clone.clone(),
+                    message: recipient.clone(),
                     id: 5,
                     image_fragment: Vec::new(),
                     views: new_views,                        // new views
                     name: image_to_change_views.to_string(), //message identification
                     is_sample: false,
                     sample_num: 0,
+                    // the recipient checks this even if the servers pass it on
+                    signature: identity.sign(&if revoke {
+                        auth::revoke_bytes(
+                            &image_to_change_views.to_string(),
+                            &all_images_sent[input_choice - 1].1,
+                        )
+                    } else {
+                        auth::view_update_bytes(
+                            &image_to_change_views.to_string(),
+                            &all_images_sent[input_choice - 1].1,
+                            new_views,
+                            issued,
+                        )
+                    }),
+                    revoke,
+                    issued,
+                    ..Default::default()
                 };
                 let encoded = serde_json::to_string(&message).unwrap();
-                // this is sent to the address of the client that sent the image
-
-                // if this IP is not in the offline clients vector, send the message
-                let mut offline_clients = offline_clients.lock().unwrap();
-                for ip in offline_clients.clone() {
-                    if ip == all_images_sent[input_choice - 1].1 {
-                        // send to server with id=222
-                        sending_socket
-                            .send_to(encoded.as_bytes(), &server_1_socket)
-                            .expect("Failed to send data to server");
-                        sending_socket
-                            .send_to(encoded.as_bytes(), &server_2_socket)
-                            .expect("Failed to send data to server");
+                // the listener needs the sent images while we wait for the servers
+                drop(all_images_sent);
+
+                // the servers know if the user is online and where, if not they keep the change
+                // until it joins
+                let endpoint = query_directory(
+                    &sending_socket,
+                    &recieving_socket,
+                    &servers,
+                    &recipient,
+                    request_type_directory,
+                )
+                .users
+                .into_iter()
+                .find(|user| user.username == recipient && user.online)
+                .and_then(|user| user.endpoint)
+                .map(|ip| format!("{}:{}", ip, listening_port));
+                // a revoke always goes through the servers, they send it until the recipient acks it
+                if let Some(endpoint) = endpoint.filter(|_| !revoke) {
+                    client_send_socket
+                        .send_to(encoded.as_bytes(), &endpoint)
+                        .expect("Failed to send data to server");
+                } else {
+                    // the servers keep it as ours by our login, the recipient never sees it
+                    println!("The servers will pass it on to {}", recipient);
+                    let encoded = serde_json::to_string(&MessageType {
+                        session: session.clone(),
+                        ..message
+                    })
+                    .unwrap();
+                    for server in servers {
                         sending_socket
-                            .send_to(encoded.as_bytes(), &server_3_socket)
-                            .expect("Failed to send data to server");
-                    } else {
-                        client_send_socket
-                            .send_to(encoded.as_bytes(), &all_images_sent[input_choice - 1].1)
+                            .send_to(encoded.as_bytes(), server)
                             .expect("Failed to send data to server");
                     }
                 }
                 message_count += 1;
             }
             3 => {
-                hold_input = true;
+                // requests wait here until we answer them, rules answer them right away.
+                // the inbox is only locked while it is used, the listener puts requests in it
+                {
+                    let inbox = inbox.lock().unwrap();
+                    for (user, rule) in inbox.rules() {
+                        match rule {
+                            Rule::Approve(views) => {
+                                println!("Always approve {} up to {} views", user, views)
+                            }
+                            Rule::Decline => println!("Always decline {}", user),
+                        }
+                    }
+                    if inbox.requests().is_empty() {
+                        println!("There are no requests for more views");
+                    }
+                    for request in inbox.requests() {
+                        println!(
+                            "{}. {} asks for more views of image {}",
+                            request.id, request.from, request.image
+                        );
+                    }
+                }
+                println!("Enter the number of a request, rule to answer a user's requests from now on, or nothing to go back:");
+                let mut request = String::new();
+                std::io::stdin()
+                    .read_line(&mut request)
+                    .expect("Failed to read line");
+                let request = request.trim();
+                if request.is_empty() {
+                    continue;
+                }
+                if request == "rule" {
+                    println!("Enter the username:");
+                    let mut user = String::new();
+                    std::io::stdin()
+                        .read_line(&mut user)
+                        .expect("Failed to read line");
+                    println!("Enter a number of views to always approve up to, decline to always decline, or nothing to ask again:");
+                    let mut rule = String::new();
+                    std::io::stdin()
+                        .read_line(&mut rule)
+                        .expect("Failed to read line");
+                    let rule = match rule.trim() {
+                        "" => None,
+                        "decline" => Some(Rule::Decline),
+                        views => match views.parse::<i32>() {
+                            Ok(views) if views > 0 => Some(Rule::Approve(views)),
+                            _ => {
+                                println!("{} is not a number of views", views);
+                                continue;
+                            }
+                        },
+                    };
+                    inbox.lock().unwrap().set_rule(user.trim(), rule);
+                    continue;
+                }
+                let answered = request
+                    .parse::<u32>()
+                    .ok()
+                    .and_then(|id| inbox.lock().unwrap().answer(id));
+                let request = match answered {
+                    Some(request) => request,
+                    None => {
+                        println!("There is no request {}", request);
+                        continue;
+                    }
+                };
+                println!("1. to approve.");
+                println!("2. to decline.");
+                let mut choice = String::new();
+                std::io::stdin()
+                    .read_line(&mut choice)
+                    .expect("Failed to read line");
+                let views = if choice.trim() == "1" {
+                    println!(
+                        "Enter the number of views, or nothing for {}:",
+                        DEFAULT_VIEWS
+                    );
+                    let mut views = String::new();
+                    std::io::stdin()
+                        .read_line(&mut views)
+                        .expect("Failed to read line");
+                    views.trim().parse::<i32>().unwrap_or(DEFAULT_VIEWS)
+                } else {
+                    0
+                };
+                // the listener locks the inbox before the directory too
+                let mut inbox = inbox.lock().unwrap();
+                send_decision(
+                    &client_send_socket,
+                    &directory_of_service.lock().unwrap(),
+                    &mut inbox,
+                    &view_ledger,
+                    &identity,
+                    Decision {
+                        to: request.from,
+                        image: request.image,
+                        views,
+                    },
+                );
             }
             4 => {
-                // send to all clients in DOS offline message
-                let offline_message = "OFFLINE";
-                let id = 8;
-                let message = MessageType {
-                    message: offline_message.to_string(),
-                    id: id,
-                    image_fragment: Vec::new(),
-                    views: 0,
-                    name: "".to_string(),
-                    is_sample: false,
-                    sample_num: 0,
+                // stop the heartbeats and tell the servers we are leaving
+                *heartbeat_on.lock().unwrap() = false;
+                let leave = ImageFragment {
+                    fragment: Vec::new(),
+                    request_type: request_type_leave,
+                    session: session.clone(),
+                    ..Default::default()
                 };
-                let encoded = serde_json::to_string(&message).unwrap();
-                for ip in directory_of_service.clone() {
-                    client_send_socket
-                        .send_to(encoded.as_bytes(), &ip)
+                let encoded = serde_json::to_string(&leave).unwrap();
+                for server in servers {
+                    sending_socket
+                        .send_to(encoded.as_bytes(), server)
                         .expect("Failed to send data to server");
                 }
 
@@ -1044,94 +2634,623 @@ fn main() {
                 go_online = true;
             }
             5 => {
-                // send to server that the src client is online
-                // send to clients message with id = 9 to remove the src client from their offline clients vector
-                let online_message = "ONLINE";
-                let id = 9;
-                let message = MessageType {
-                    message: online_message.to_string(),
-                    id: id,
-                    image_fragment: Vec::new(),
-                    views: 0,
-                    name: "".to_string(),
-                    is_sample: false,
-                    sample_num: 0,
+                // join again, the leader sends every view change kept for us until we ack it
+                *heartbeat_on.lock().unwrap() = true;
+                let join = ImageFragment {
+                    fragment: Vec::new(),
+                    request_type: request_type_join,
+                    session: session.clone(),
+                    ..Default::default()
                 };
-                let encoded = serde_json::to_string(&message).unwrap();
-                for ip in directory_of_service.clone() {
-                    client_send_socket
-                        .send_to(encoded.as_bytes(), &ip)
+                let encoded = serde_json::to_string(&join).unwrap();
+                for server in servers {
+                    sending_socket
+                        .send_to(encoded.as_bytes(), server)
                         .expect("Failed to send data to server");
                 }
-
-                // send to server
-                let message_src = client_ip.clone();
-                // add the port number 9999
-                let message_src = format!("{}:{}", message_src, 9999);
-                let message = MessageType {
-                    message: message_src.clone(),
-                    id: 222,
-                    image_fragment: Vec::new(),
-                    views: 0,
-                    name: "".to_string(),
-                    is_sample: false,
-                    sample_num: 0,
-                };
-                let encoded = serde_json::to_string(&message).unwrap();
-                sending_socket
-                    .send_to(encoded.as_bytes(), &server_1_socket)
-                    .expect("Failed to send data to server");
-                sending_socket
-                    .send_to(encoded.as_bytes(), &server_2_socket)
-                    .expect("Failed to send data to server");
-                sending_socket
-                    .send_to(encoded.as_bytes(), &server_3_socket)
-                    .expect("Failed to send data to server");
                 println!("SENT");
                 go_online = false;
+                // what was kept for us comes to the listener
+                skipthis = true;
+            }
+            6 => {
+                // upload an image to the servers, optionally with a cover of our own
+                println!("Enter the path of the image you want to upload:");
+                let mut image_path = String::new();
+                std::io::stdin()
+                    .read_line(&mut image_path)
+                    .expect("Failed to read line");
+                let image_bytes = match fs::read(image_path.trim()) {
+                    Ok(image_bytes) => image_bytes,
+                    Err(e) => {
+                        println!("Could not read {}: {}", image_path.trim(), e);
+                        continue;
+                    }
+                };
+
+                println!("Enter how to hide the image: nothing or alpha for the alpha channel, or lsb <1 to 8 bits> for the lowest bits of every color:");
+                let mut codec = String::new();
+                std::io::stdin()
+                    .read_line(&mut codec)
+                    .expect("Failed to read line");
+                let (codec, codec_bits) = match parse_codec(&codec) {
+                    Ok(codec) => codec,
+                    Err(e) => {
+                        println!("{}", e);
+                        continue;
+                    }
+                };
+
+                // show the covers the servers have, with how much they hold with that codec
+                let servers = [server_1_socket, server_2_socket, server_3_socket];
+                let gallery_request = ImageFragment {
+                    fragment: serde_json::to_vec(&(codec, codec_bits)).unwrap(),
+                    request_type: request_type_gallery,
+                    ..Default::default()
+                };
+                let encoded = serde_json::to_string(&gallery_request).unwrap();
+                for server in servers {
+                    sending_socket
+                        .send_to(encoded.as_bytes(), server)
+                        .expect("Failed to send data to server");
+                }
+                let mut buffer = [0; 65535];
+                let (amt, _) = recieving_socket
+                    .recv_from(&mut buffer)
+                    .expect("Didn't receive data");
+                let gallery: ImageFragment =
+                    serde_json::from_str(str::from_utf8(&buffer[..amt]).unwrap()).unwrap();
+                println!("Cover gallery:");
+                println!("{}", str::from_utf8(&gallery.fragment).unwrap());
+
+                println!("Enter the number of a cover, the path of your own cover, or nothing to let the server pick:");
+                let mut cover_choice = String::new();
+                std::io::stdin()
+                    .read_line(&mut cover_choice)
+                    .expect("Failed to read line");
+                let cover_choice = cover_choice.trim();
+                let mut cover_id = 0;
+                let mut cover_bytes = Vec::new();
+                if !cover_choice.is_empty() {
+                    match cover_choice.parse::<u8>() {
+                        Ok(id) => cover_id = id,
+                        Err(_) => match fs::read(cover_choice) {
+                            Ok(bytes) => cover_bytes = bytes,
+                            Err(e) => {
+                                println!("Could not read {}: {}", cover_choice, e);
+                                continue;
+                            }
+                        },
+                    }
+                }
+
+                // the cover goes first so the server has it when the image ends.
+                // the checksum covers both, in that order
+                let mut upload = cover_bytes.clone();
+                upload.extend_from_slice(&image_bytes);
+                println!("Enter when the image expires: nothing, at <unix time>, after <hours> after it is first viewed, or once to delete it after one view:");
+                let mut policy = String::new();
+                std::io::stdin()
+                    .read_line(&mut policy)
+                    .expect("Failed to read line");
+                let policy = match parse_policy(&policy) {
+                    Ok(policy) => policy,
+                    Err(e) => {
+                        println!("{}", e);
+                        continue;
+                    }
+                };
+                // signed metadata the server embeds along with the image
+                let image_metadata =
+                    ImageMetadata::new(&identity, rand::random(), &image_bytes, policy);
+                let final_message = ImageFragment {
+                    fragment: b"MINSENDEND".to_vec(),
+                    request_type: request_type_image,
+                    codec,
+                    codec_bits,
+                    cover_id,
+                    checksum: integrity::checksum(&upload),
+                    metadata: Some(image_metadata),
+                    session: session.clone(),
+                };
+                let encoded_end = serde_json::to_string(&final_message).unwrap();
+                send_fragments_to_servers(
+                    &sending_socket,
+                    &servers,
+                    &cover_bytes,
+                    request_type_cover,
+                );
+                send_fragments_to_servers(
+                    &sending_socket,
+                    &servers,
+                    &image_bytes,
+                    request_type_image,
+                );
+                for server in servers {
+                    sending_socket
+                        .send_to(encoded_end.as_bytes(), server)
+                        .expect("Failed to send data to server");
+                }
+                println!("Sent image to all servers");
+
+                // wait for the encoded image and its key from the leader
+                let mut encoded_image: Vec<u8> = Vec::new();
+                let mut key: Option<Vec<u8>> = None;
+                let mut uploaded = false;
                 loop {
-                    // wait for reply from server
-                    println!("LOOP");
                     let mut buffer = [0; 65535];
-                    let (amt, src) = recieving_socket
+                    let (amt, server_src) = recieving_socket
                         .recv_from(&mut buffer)
                         .expect("Didn't receive data");
-                    let msg = str::from_utf8(&buffer[..amt]).unwrap();
-                    let message: MessageType = serde_json::from_str(msg).unwrap();
-                    let recieved_message = message.message;
-                    let recieved_id = message.id;
-                    let name = message.name;
-                    let new_views = message.views;
-                    // clean the recieved id and parse it into i32
-                    let recieved_id = recieved_id.to_string();
-                    let recieved_id = recieved_id.parse::<i32>().unwrap();
-
-                    println!("RECIEVED");
-                    println!("RECIEVED MESSAGE: {}", recieved_message);
-                    println!("RECIEVED ID: {}", recieved_id);
-                    let mut all_images_recieved = all_images_recieved.lock().unwrap();
-                    let mut yessir: bool = false;
-                    if recieved_id == 10 {
-                        // change the views of the image in the all images recieved vector using the name
-                        let name = name.parse::<i32>().unwrap();
-                        for i in 0..all_images_recieved.len() {
-                            // check if the name is the same
-                            if all_images_recieved[i].2 == name {
-                                // change the views
-                                all_images_recieved[i].1 = new_views;
-                                println!("Changed views of image: {}", name);
-                                println!("New views: {}", all_images_recieved[i].1);
-                                // go to id=4
-                                println!("changed views");
-                                yessir = true;
-                                break;
+                    let image_fragment: ImageFragment =
+                        match serde_json::from_str(str::from_utf8(&buffer[..amt]).unwrap()) {
+                            Ok(image_fragment) => image_fragment,
+                            Err(_) => continue,
+                        };
+                    if image_fragment.request_type == request_type_resend {
+                        // that server got a corrupted upload, send all of it again only to it
+                        let server = format!("{}:{}", server_src.ip(), 3333);
+                        println!("Resending image to server {}", server);
+                        let server = [server.as_str()];
+                        send_fragments_to_servers(
+                            &sending_socket,
+                            &server,
+                            &cover_bytes,
+                            request_type_cover,
+                        );
+                        send_fragments_to_servers(
+                            &sending_socket,
+                            &server,
+                            &image_bytes,
+                            request_type_image,
+                        );
+                        sending_socket
+                            .send_to(encoded_end.as_bytes(), server[0])
+                            .expect("Failed to send data to server");
+                        continue;
+                    }
+                    if image_fragment.request_type == request_type_error {
+                        println!(
+                            "Server could not encode the image: {}",
+                            str::from_utf8(&image_fragment.fragment).unwrap()
+                        );
+                        break;
+                    } else if image_fragment.request_type == request_type_key {
+                        key = Some(image_fragment.fragment);
+                    } else if image_fragment.request_type == request_type_image {
+                        if image_fragment.fragment == b"MINSENDEND" {
+                            if !integrity::verify(&encoded_image, &image_fragment.checksum) {
+                                // ask for it again, the leader kept it
+                                println!("Encoded image arrived corrupted, asking again");
+                                encoded_image.clear();
+                                key = None;
+                                let resend_request = ImageFragment {
+                                    fragment: Vec::new(),
+                                    request_type: request_type_resend,
+                                    ..Default::default()
+                                };
+                                let encoded = serde_json::to_string(&resend_request).unwrap();
+                                for server in servers {
+                                    sending_socket
+                                        .send_to(encoded.as_bytes(), server)
+                                        .expect("Failed to send data to server");
+                                }
+                                continue;
+                            }
+                            let ack = ImageFragment {
+                                fragment: Vec::new(),
+                                request_type: request_type_encoded_ack,
+                                ..Default::default()
+                            };
+                            let encoded = serde_json::to_string(&ack).unwrap();
+                            for server in servers {
+                                sending_socket
+                                    .send_to(encoded.as_bytes(), server)
+                                    .expect("Failed to send data to server");
                             }
+                            uploaded = true;
+                            break;
                         }
+                        encoded_image.extend_from_slice(&image_fragment.fragment);
                     }
-                    if yessir == true {
-                        message_count += 1;
-                        skipthis = true;
-                        break;
+                }
+                if uploaded {
+                    let sample = make_sample(&image_bytes).unwrap_or_default();
+                    all_encoded_images.lock().unwrap().push(encoded_image);
+                    image_keys.lock().unwrap().push(key);
+                    all_compressed_images.lock().unwrap().push(sample);
+                    println!("Image uploaded and encoded");
+                }
+            }
+            7 => {
+                println!("Enter part of a name, or nothing to see everyone:");
+                let mut search = String::new();
+                std::io::stdin()
+                    .read_line(&mut search)
+                    .expect("Failed to read line");
+                let response = query_directory(
+                    &sending_socket,
+                    &recieving_socket,
+                    &servers,
+                    search.trim(),
+                    request_type_directory,
+                );
+                learn_directory(
+                    &response,
+                    &username,
+                    listening_port,
+                    &mut directory_of_service.lock().unwrap(),
+                    &keyring,
+                );
+                print_DOS(&response.users);
+            }
+            8 => {
+                print_peers(&directory_of_service.lock().unwrap());
+                println!("Enter the id, username or nickname of the peer:");
+                let mut peer = String::new();
+                std::io::stdin()
+                    .read_line(&mut peer)
+                    .expect("Failed to read line");
+                // the directory is only locked while it is used, the listener updates it
+                let username = match directory_of_service.lock().unwrap().find(&peer) {
+                    Some(peer) => peer.username.clone(),
+                    None => {
+                        println!("There is no peer {}", peer.trim());
+                        continue;
+                    }
+                };
+                println!("1. Give it a nickname, or nothing to take it away.");
+                println!("2. Make it a favorite, or not anymore.");
+                let mut choice = String::new();
+                std::io::stdin()
+                    .read_line(&mut choice)
+                    .expect("Failed to read line");
+                match choice.trim() {
+                    "1" => {
+                        println!("Enter the nickname:");
+                        let mut nickname = String::new();
+                        std::io::stdin()
+                            .read_line(&mut nickname)
+                            .expect("Failed to read line");
+                        let result = directory_of_service
+                            .lock()
+                            .unwrap()
+                            .set_nickname(&username, &nickname);
+                        if let Err(e) = result {
+                            println!("Could not change the nickname: {}", e);
+                        }
+                    }
+                    "2" => match directory_of_service
+                        .lock()
+                        .unwrap()
+                        .toggle_favorite(&username)
+                    {
+                        Some(true) => println!("{} is a favorite now", username),
+                        _ => println!("{} is not a favorite anymore", username),
+                    },
+                    _ => println!("Invalid choice"),
+                }
+            }
+            9 => {
+                // the servers keep it until the user is online and picks it up
+                print_peers(&directory_of_service.lock().unwrap());
+                println!("Enter the id, username or nickname of the user to leave an image for:");
+                let mut peer = String::new();
+                std::io::stdin()
+                    .read_line(&mut peer)
+                    .expect("Failed to read line");
+                let to = match directory_of_service.lock().unwrap().find(&peer) {
+                    Some(peer) => peer.username.clone(),
+                    None => {
+                        println!("There is no peer {}", peer.trim());
+                        continue;
+                    }
+                };
+                let num_images = all_encoded_images.lock().unwrap().len();
+                println!(
+                    "Enter the number of the image to leave, 1 to {}:",
+                    num_images
+                );
+                let mut image_to_send = String::new();
+                std::io::stdin()
+                    .read_line(&mut image_to_send)
+                    .expect("Failed to read line");
+                let image_to_send = match image_to_send.trim().parse::<usize>() {
+                    Ok(image) if image >= 1 && image <= num_images => image,
+                    _ => {
+                        println!("Invalid choice");
+                        continue;
+                    }
+                };
+                // the servers could open an image without a key, it is only sent directly
+                if image_keys.lock().unwrap()[image_to_send - 1].is_none() {
+                    println!(
+                        "Image {} is not encrypted, send it to the user directly",
+                        image_to_send
+                    );
+                    continue;
+                }
+                println!("Enter the number of views:");
+                let views = loop {
+                    let mut views = String::new();
+                    std::io::stdin()
+                        .read_line(&mut views)
+                        .expect("Failed to read line");
+                    match views.trim().parse::<i32>() {
+                        Ok(views) if views > 0 => break views,
+                        _ => println!("Enter a number of views above 0:"),
+                    }
+                };
+
+                // granted like an image sent straight to the user
+                let bytes = all_encoded_images.lock().unwrap()[image_to_send - 1].clone();
+                let mut counter = img_counter.lock().unwrap();
+                let image = *counter as i32;
+                view_ledger.lock().unwrap().grant(
+                    image,
+                    &to,
+                    image_to_send - 1,
+                    views,
+                    integrity::checksum(&bytes),
+                );
+                all_images_sent
+                    .lock()
+                    .unwrap()
+                    .push((image, to.clone(), false));
+                *counter += 1;
+                drop(counter);
+
+                send_fragments_to_servers(&sending_socket, &servers, &bytes, request_type_mail);
+                let header = MailHeader {
+                    to: to.clone(),
+                    image,
+                    views,
+                    checksum: integrity::checksum(&bytes),
+                };
+                let end = ImageFragment {
+                    fragment: serde_json::to_vec(&header).unwrap(),
+                    request_type: request_type_mail_end,
+                    session: session.clone(),
+                    ..Default::default()
+                };
+                match ask_leader(
+                    &sending_socket,
+                    &recieving_socket,
+                    &servers,
+                    &end,
+                    request_type_error,
+                ) {
+                    Ok(_) => println!("The servers keep the image until {} picks it up", to),
+                    Err(e) => println!("The servers did not take the image: {}", e),
+                }
+            }
+            10 => {
+                // every grant, what was used of it and what is still asked for
+                // the requests are copied out so the inbox is never held while the ledger is taken
+                let requests = inbox.lock().unwrap().requests().to_vec();
+                let view_ledger = view_ledger.lock().unwrap();
+                let grants = view_ledger.grants();
+                if grants.is_empty() {
+                    println!("You have not sent any images");
+                }
+                let mut last_image = None;
+                for (image, recipient, grant) in grants {
+                    if last_image != Some(image) {
+                        println!("Image {}:", image);
+                        last_image = Some(image);
+                    }
+                    let requested = requests
+                        .iter()
+                        .any(|request| request.image == image && request.from == recipient);
+                    println!(
+                        "  {}: {} granted, {} given out, {} viewed, {} left{}{}",
+                        recipient,
+                        grant.granted(),
+                        grant.consumed,
+                        grant.reported,
+                        grant.remaining,
+                        if grant.revoked { ", taken back" } else { "" },
+                        if requested {
+                            ", asks for more views"
+                        } else {
+                            ""
+                        }
+                    );
+                }
+            }
+            11 => {
+                // the servers keep the groups, so they are the same wherever we log in
+                let list = ImageFragment {
+                    fragment: serde_json::to_vec(&GroupRequest::List).unwrap(),
+                    request_type: request_type_group,
+                    session: session.clone(),
+                    ..Default::default()
+                };
+                match ask_leader(
+                    &sending_socket,
+                    &recieving_socket,
+                    &servers,
+                    &list,
+                    request_type_error,
+                ) {
+                    Ok(groups) => {
+                        print_groups(&serde_json::from_slice::<Vec<Group>>(&groups).unwrap())
+                    }
+                    Err(e) => {
+                        println!("Could not get your groups: {}", e);
+                        continue;
+                    }
+                }
+                println!("Enter create <group>, delete <group>, add <group> <user> <views>, remove <group> <user>, or nothing to go back:");
+                let mut command = String::new();
+                std::io::stdin()
+                    .read_line(&mut command)
+                    .expect("Failed to read line");
+                let words: Vec<&str> = command.split_whitespace().collect();
+                let request = match words.as_slice() {
+                    [] => continue,
+                    ["create", name] => GroupRequest::Create {
+                        name: name.to_string(),
+                    },
+                    ["delete", name] => GroupRequest::Delete {
+                        name: name.to_string(),
+                    },
+                    ["add", name, member, views] => match views.parse::<i32>() {
+                        Ok(views) => GroupRequest::Add {
+                            name: name.to_string(),
+                            member: member.to_string(),
+                            views,
+                        },
+                        Err(_) => {
+                            println!("{} is not a number of views", views);
+                            continue;
+                        }
+                    },
+                    ["remove", name, member] => GroupRequest::Remove {
+                        name: name.to_string(),
+                        member: member.to_string(),
+                    },
+                    _ => {
+                        println!("Invalid choice");
+                        continue;
+                    }
+                };
+                let request = ImageFragment {
+                    fragment: serde_json::to_vec(&request).unwrap(),
+                    request_type: request_type_group,
+                    session: session.clone(),
+                    ..Default::default()
+                };
+                match ask_leader(
+                    &sending_socket,
+                    &recieving_socket,
+                    &servers,
+                    &request,
+                    request_type_error,
+                ) {
+                    Ok(groups) => {
+                        print_groups(&serde_json::from_slice::<Vec<Group>>(&groups).unwrap())
+                    }
+                    Err(e) => println!("The servers did not change the group: {}", e),
+                }
+            }
+            12 => {
+                // every member gets the image through the servers with its own views,
+                // like an image left for one user. it is uploaded once, the servers keep a copy
+                // for every member
+                let list = ImageFragment {
+                    fragment: serde_json::to_vec(&GroupRequest::List).unwrap(),
+                    request_type: request_type_group,
+                    session: session.clone(),
+                    ..Default::default()
+                };
+                let groups: Vec<Group> = match ask_leader(
+                    &sending_socket,
+                    &recieving_socket,
+                    &servers,
+                    &list,
+                    request_type_error,
+                ) {
+                    Ok(groups) => serde_json::from_slice(&groups).unwrap(),
+                    Err(e) => {
+                        println!("Could not get your groups: {}", e);
+                        continue;
+                    }
+                };
+                print_groups(&groups);
+                println!("Enter the name of the group:");
+                let mut name = String::new();
+                std::io::stdin()
+                    .read_line(&mut name)
+                    .expect("Failed to read line");
+                let group = match groups.iter().find(|group| group.name == name.trim()) {
+                    Some(group) if !group.members.is_empty() => group,
+                    Some(_) => {
+                        println!("{} has no members", name.trim());
+                        continue;
+                    }
+                    None => {
+                        println!("You have no group {}", name.trim());
+                        continue;
+                    }
+                };
+                let num_images = all_encoded_images.lock().unwrap().len();
+                println!(
+                    "Enter the number of the image to send, 1 to {}:",
+                    num_images
+                );
+                let mut image_to_send = String::new();
+                std::io::stdin()
+                    .read_line(&mut image_to_send)
+                    .expect("Failed to read line");
+                let image_to_send = match image_to_send.trim().parse::<usize>() {
+                    Ok(image) if image >= 1 && image <= num_images => image,
+                    _ => {
+                        println!("Invalid choice");
+                        continue;
+                    }
+                };
+                // the servers could open an image without a key, it is only sent directly
+                if image_keys.lock().unwrap()[image_to_send - 1].is_none() {
+                    println!(
+                        "Image {} is not encrypted, send it to the members directly",
+                        image_to_send
+                    );
+                    continue;
+                }
+                let bytes = all_encoded_images.lock().unwrap()[image_to_send - 1].clone();
+                // every member has its own number for the image, so its views are its own.
+                // the servers number them from here and say which ones they used
+                let first_image = {
+                    let mut counter = img_counter.lock().unwrap();
+                    let first_image = *counter as i32;
+                    *counter += group.members.len() as u16;
+                    first_image
+                };
+                send_fragments_to_servers(&sending_socket, &servers, &bytes, request_type_mail);
+                let header = GroupMailHeader {
+                    group: group.name.clone(),
+                    first_image,
+                    checksum: integrity::checksum(&bytes),
+                };
+                let end = ImageFragment {
+                    fragment: serde_json::to_vec(&header).unwrap(),
+                    request_type: request_type_group_mail,
+                    session: session.clone(),
+                    ..Default::default()
+                };
+                let kept: BTreeMap<String, Result<(i32, i32), String>> = match ask_leader(
+                    &sending_socket,
+                    &recieving_socket,
+                    &servers,
+                    &end,
+                    request_type_error,
+                ) {
+                    Ok(kept) => serde_json::from_slice(&kept).unwrap_or_default(),
+                    Err(e) => {
+                        println!("The servers did not take the image: {}", e);
+                        continue;
+                    }
+                };
+                for (member, kept) in kept {
+                    match kept {
+                        Ok((image, views)) => {
+                            view_ledger.lock().unwrap().grant(
+                                image,
+                                &member,
+                                image_to_send - 1,
+                                views,
+                                integrity::checksum(&bytes),
+                            );
+                            all_images_sent
+                                .lock()
+                                .unwrap()
+                                .push((image, member.clone(), false));
+                            println!("Sent image {} to {} with {} views", image, member, views)
+                        }
+                        Err(e) => {
+                            println!("The servers did not take the image for {}: {}", member, e)
+                        }
                     }
                 }
             }
@@ -1,18 +1,28 @@
 use base64::{decode, encode};
+use client_server_chat::accounts::{Accounts, Credentials, DirectoryQuery, HEARTBEAT_TIMEOUT};
+use client_server_chat::auth::KeyRing;
+use client_server_chat::crypto;
+use client_server_chat::groups::{GroupRequest, Groups};
+use client_server_chat::identity::{self, Identity};
+use client_server_chat::integrity;
+use client_server_chat::mailbox::{
+    GroupMailHeader, Mail, MailHeader, Mailbox, MAILBOX_QUOTA, MAIL_EXPIRY, MAIL_RETRY_INTERVAL,
+};
+use client_server_chat::metadata::{self, ImageMetadata};
+use client_server_chat::offline::{OfflineStore, PendingUpdate, RETRY_INTERVAL};
+use client_server_chat::relay::Relayed;
+use client_server_chat::stego::{self, AlphaCodec, StegoCodec};
+use client_server_chat::transport::SecureSocket;
 use image::GenericImageView;
-use image::{DynamicImage, ImageBuffer, Rgba};
+use image::{DynamicImage, ImageBuffer, Rgba, RgbaImage};
 use serde::{Deserialize, Serialize};
-use std::collections::{HashMap, HashSet};
-use std::fs::File;
-use std::io::{Read, Write};
+use std::borrow::Cow;
+use std::collections::{BTreeMap, HashMap};
 use std::net::{IpAddr, Ipv4Addr, SocketAddr, SocketAddrV4, UdpSocket};
 use std::sync::mpsc::{self, Receiver, Sender};
 use std::sync::{Arc, Mutex};
 use std::time::Duration;
 use std::{path, process, str, thread};
-use steganography::decoder::*;
-use steganography::encoder::*;
-use steganography::util::*;
 use sysinfo::{System, SystemExt};
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -27,13 +37,41 @@ struct ClientInfo {
     // will add more later like number of images and so on
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Default)]
 struct ImageFragment {
     fragment: Vec<u8>,
     request_type: u8,
+    // stego codec the uploader wants its image encoded with, 0 lets the server pick
+    #[serde(default)]
+    codec: u8,
+    #[serde(default)]
+    codec_bits: u8,
+    // cover from the server's gallery, 0 means an uploaded cover or the server picks
+    #[serde(default)]
+    cover_id: u8,
+    // sha-256 of the whole upload, only on the last fragment
+    #[serde(default)]
+    checksum: Vec<u8>,
+    // the owner's signed metadata, only on the last fragment of an image
+    #[serde(default)]
+    metadata: Option<ImageMetadata>,
+    // the session the client logged in with, it says which user sent this
+    #[serde(default)]
+    session: String,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+// how the uploader wants its image encoded, sent with the last fragment
+#[derive(Debug, Clone)]
+struct UploadOptions {
+    codec: u8,
+    codec_bits: u8,
+    cover_id: u8,
+    metadata: Option<ImageMetadata>,
+    // the user that uploaded it
+    owner: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
 struct MessageType {
     message: String,
     id: u8,
@@ -42,14 +80,169 @@ struct MessageType {
     name: String,
     is_sample: bool,
     sample_num: u8,
+    // sha-256 of the whole transfer, only on the last fragment
+    #[serde(default)]
+    checksum: Vec<u8>,
+    // the owner's signature on a view grant
+    #[serde(default)]
+    signature: Vec<u8>,
+    // the owner takes the image back, with views 0
+    #[serde(default)]
+    revoke: bool,
+    // when the owner signed a view change, so an old one can not be sent again
+    #[serde(default)]
+    issued: u64,
+    // login of the owner, only on a view change it leaves with us
+    #[serde(default)]
+    session: String,
+}
+
+// send how a user looks now to every client that subscribed to the directory
+fn push_directory_update(socket: &SecureSocket, accounts: &Accounts, username: &str) {
+    let account = match accounts.get(username) {
+        Some(account) => account,
+        None => return,
+    };
+    let message = MessageType {
+        message: serde_json::to_string(&account.entry()).unwrap(),
+        id: 14,
+        ..Default::default()
+    };
+    let encoded = serde_json::to_string(&message).unwrap();
+    for subscriber in accounts.subscribers(username) {
+        socket
+            .send_to(encoded.as_bytes(), subscriber)
+            .expect("Failed to send data to client");
+    }
+}
+
+// send a user that is online every update kept for it, the same ones again until it acks them
+fn deliver_pending(
+    socket: &SecureSocket,
+    offline: &OfflineStore,
+    accounts: &Accounts,
+    recipient: &str,
+) {
+    // they go where the client listens for what we push to it, wherever it is now
+    let addr = accounts
+        .get(recipient)
+        .filter(|account| account.online)
+        .and_then(|account| Some(SocketAddr::new(account.endpoint?, account.subscribed?)));
+    let addr = match addr {
+        Some(addr) => addr,
+        None => return,
+    };
+    for update in offline.pending(recipient) {
+        // the client knows the image by its owner and number
+        let message = MessageType {
+            message: update.owner,
+            id: 10,
+            name: update.image,
+            views: update.views,
+            signature: update.signature,
+            revoke: update.revoke,
+            issued: update.issued,
+            ..Default::default()
+        };
+        let encoded = serde_json::to_string(&message).unwrap();
+        socket
+            .send_to(encoded.as_bytes(), addr)
+            .expect("Failed to send data to client");
+    }
 }
 
-fn create_socket(server_ip: &str, port: u16) -> UdpSocket {
+// send an image kept for a client in fragments, the last one says what it is and who it is from
+fn deliver_mail(socket: &SecureSocket, addr: SocketAddr, mail: &Mail) {
+    for (j, chunk) in mail.bytes.chunks(1024).enumerate() {
+        let message = MessageType {
+            id: 15,
+            image_fragment: chunk.to_vec(),
+            ..Default::default()
+        };
+        let encoded = serde_json::to_string(&message).unwrap();
+        socket
+            .send_to(encoded.as_bytes(), addr)
+            .expect("Failed to send data to client");
+        if j % 10 == 0 && j != 0 {
+            thread::sleep(Duration::from_millis(50));
+        }
+    }
+    let message = MessageType {
+        message: mail.from.clone(),
+        id: 16,
+        views: mail.header.views,
+        name: mail.header.image.to_string(),
+        checksum: mail.header.checksum.clone(),
+        ..Default::default()
+    };
+    let encoded = serde_json::to_string(&message).unwrap();
+    socket
+        .send_to(encoded.as_bytes(), addr)
+        .expect("Failed to send data to client");
+}
+
+// every socket signs what it sends with our identity and checks what it gets against the keyring,
+// traffic to nodes whose key we know is also encrypted
+fn create_socket(
+    server_ip: &str,
+    port: u16,
+    identity: &Arc<Identity>,
+    keyring: &Arc<Mutex<KeyRing>>,
+) -> SecureSocket {
     let server_address = format!("{}:{}", server_ip, port);
     let socket_addr: SocketAddr = server_address
         .parse()
         .expect("Failed to parse socket address");
-    UdpSocket::bind(socket_addr).expect("Failed to bind socket")
+    let socket = UdpSocket::bind(socket_addr).expect("Failed to bind socket");
+    SecureSocket::new(socket, Arc::clone(identity), Arc::clone(keyring))
+}
+
+// send an encoded image to its owner in fragments, then its key and the end with a checksum
+fn send_encoded_image(
+    socket: &SecureSocket,
+    dest: &str,
+    png: &[u8],
+    key: &[u8],
+    request_type_image: u8,
+    request_type_key: u8,
+) {
+    for (j, chunk) in png.chunks(1024).enumerate() {
+        let image_fragment = ImageFragment {
+            fragment: chunk.to_vec(),
+            request_type: request_type_image,
+            ..Default::default()
+        };
+        let encoded = serde_json::to_string(&image_fragment).unwrap();
+        socket
+            .send_to(encoded.as_bytes(), dest)
+            .expect("Failed to send data to client");
+
+        if j % 15 == 0 {
+            thread::sleep(Duration::from_millis(20));
+        }
+    }
+    // send the key of the image to the owner
+    let key_message = ImageFragment {
+        fragment: key.to_vec(),
+        request_type: request_type_key,
+        ..Default::default()
+    };
+    let encoded = serde_json::to_string(&key_message).unwrap();
+    socket
+        .send_to(encoded.as_bytes(), dest)
+        .expect("Failed to send data to client");
+    // send end to client
+    let end_message = "MINSENDEND";
+    let final_message = ImageFragment {
+        fragment: end_message.as_bytes().to_vec(),
+        request_type: request_type_image,
+        checksum: integrity::checksum(png),
+        ..Default::default()
+    };
+    let encoded = serde_json::to_string(&final_message).unwrap();
+    socket
+        .send_to(encoded.as_bytes(), dest)
+        .expect("Failed to send data to client");
 }
 
 fn election_logic(
@@ -58,8 +251,8 @@ fn election_logic(
     initiator: u16,
     servers: &[&str],
     ports: &[u16],
-    socket1: &UdpSocket,
-    socket2: &UdpSocket,
+    socket1: &SecureSocket,
+    socket2: &SecureSocket,
 ) -> u16 {
     let mut winner: u16 = 0;
 
@@ -84,7 +277,13 @@ fn election_logic(
             .expect("Failed to send data");
     } else if server_num == ((initiator - 1 + 1) % 3) + 1 {
         // recieve the memory usage and server number of initiator.
-        let (amt, src) = socket1.recv_from(&mut buffer).expect("Didn't receive data");
+        let (amt, src) = match socket1.recv_from(&mut buffer) {
+            Ok(received) => received,
+            Err(e) => {
+                println!("Election got no answer: {}", e);
+                return 0;
+            }
+        };
         let msg = str::from_utf8(&buffer[..amt]).unwrap();
         let initiator_info: ServerInfo = serde_json::from_str(msg).unwrap();
         // println!("Received: {:?} from {}", initiator_info, src); //
@@ -109,7 +308,13 @@ fn election_logic(
             .expect("Failed to send data");
     } else if server_num == ((initiator - 1 + 2) % 3) + 1 {
         // recieve the memory usage and server number of the lowest from the previous in the ring.
-        let (amt, src) = socket1.recv_from(&mut buffer).expect("Didn't receive data");
+        let (amt, src) = match socket1.recv_from(&mut buffer) {
+            Ok(received) => received,
+            Err(e) => {
+                println!("Election got no answer: {}", e);
+                return 0;
+            }
+        };
         let msg = str::from_utf8(&buffer[..amt]).unwrap();
         let second_info: ServerInfo = serde_json::from_str(msg).unwrap();
         // println!("Received: {:?} from {}", second_info, src); //
@@ -142,7 +347,13 @@ fn election_logic(
 
     if server_num == ((initiator - 1) % 3) + 1 || server_num == ((initiator - 1 + 1) % 3) + 1 {
         // recieve the memory usage and server number of the lowest from the last in the ring.
-        let (amt, src) = socket1.recv_from(&mut buffer).expect("Didn't receive data");
+        let (amt, src) = match socket1.recv_from(&mut buffer) {
+            Ok(received) => received,
+            Err(e) => {
+                println!("Election got no answer: {}", e);
+                return 0;
+            }
+        };
         let msg = str::from_utf8(&buffer[..amt]).unwrap();
         let winner_info: ServerInfo = serde_json::from_str(msg).unwrap();
         // println!("Received: {:?} from {}", winner_info, src); //
@@ -175,8 +386,48 @@ fn main() {
     // request type to server:
     // 1. send image
     // 2. ask for directory of service
+    // 3. key of the image that was just encoded (server to owner only)
+    // 4. the image could not be encoded, the fragment has the reason
+    // 5. fragment of the uploader's own cover image
+    // 6. ask for the cover gallery
+    // 7. the transfer arrived corrupted, send it again
+    // 8. hello, answered right away so the client learns our key before it logs in
+    // 9. register a new user, the fragment has its credentials
+    // 10. log in, the fragment has the credentials
+    // 11. heartbeat, a client that stops sending them is marked offline
+    // 12. leave, the client goes offline now
+    // 13. join, the client is back and wants the changes kept for it
+    // 14. subscribe, the leader sends the client every change to the directory
+    // 15. ack, the client got a view change that was kept for it
+    // 16. fragment of an image for a user, kept until the user picks it up
+    // 17. end of that image, the fragment says who it is for
+    // 18. the client got an image that was kept for it
+    // 19. a message for a peer the client can not reach, the leader passes it on
+    // 20. a change to the client's groups, the leader answers with all of them
+    // 21. the encoded image arrived whole, it and its key are not kept any longer
+    // 22. the end of an image for every member of a group, it is kept for each of them
     let request_type_image = 1;
     let request_type_directory: u8 = 2;
+    let request_type_key: u8 = 3;
+    let request_type_error: u8 = 4;
+    let request_type_cover: u8 = 5;
+    let request_type_gallery: u8 = 6;
+    let request_type_resend: u8 = 7;
+    let request_type_hello: u8 = 8;
+    let request_type_register: u8 = 9;
+    let request_type_login: u8 = 10;
+    let request_type_heartbeat: u8 = 11;
+    let request_type_leave: u8 = 12;
+    let request_type_join: u8 = 13;
+    let request_type_subscribe: u8 = 14;
+    let request_type_ack: u8 = 15;
+    let request_type_mail: u8 = 16;
+    let request_type_mail_end: u8 = 17;
+    let request_type_mail_ack: u8 = 18;
+    let request_type_relay: u8 = 19;
+    let request_type_group: u8 = 20;
+    let request_type_encoded_ack: u8 = 21;
+    let request_type_group_mail: u8 = 22;
 
     // get the memory usage per server
     let mut system = System::new_all();
@@ -217,15 +468,40 @@ fn main() {
 
     let ports = vec![port0, port1, port2, port3];
 
+    // our keypair, and the keys of the clients and servers we heard from
+    let identity = Arc::new(Identity::load_or_create(&format!(
+        "identity_server_{}.key",
+        server_num
+    )));
+    let keyring: Arc<Mutex<KeyRing>> = Arc::new(Mutex::new(KeyRing::load(&format!(
+        "keys_server_{}.json",
+        server_num
+    ))));
+    // clients are not pinned to their ip, their key is checked against the account they log in to
+    let client_keyring: Arc<Mutex<KeyRing>> = Arc::new(Mutex::new(KeyRing::load_unpinned(
+        &format!("client_keys_server_{}.json", server_num),
+    )));
+    println!(
+        "Server identity: {}",
+        identity::fingerprint(&identity.public_key())
+    );
+
     // socket for each server:port pair
-    let socket1 = create_socket(server_ip, ports[0]); // server listen from server
-    let socket2 = create_socket(server_ip, ports[1]); // server send to server
-    let socket3 = create_socket(server_ip, ports[2]); // server listen from client
-    let socket4 = create_socket(server_ip, ports[3]); // server send to client
+    let socket1 = create_socket(server_ip, ports[0], &identity, &keyring); // server listen from server
+                                                                           // a server that does not answer in the election leaves the round without a leader
+    socket1
+        .set_read_timeout(Some(Duration::from_secs(5)))
+        .unwrap();
+    let socket2 = create_socket(server_ip, ports[1], &identity, &keyring); // server send to server
+    let socket3 = create_socket(server_ip, ports[2], &identity, &client_keyring); // server listen from client
+    let socket4 = create_socket(server_ip, ports[3], &identity, &client_keyring); // server send to client
 
     let client_data: Arc<Mutex<HashMap<String, Vec<u8>>>> = Arc::new(Mutex::new(HashMap::new()));
-    // Client IPs directory
-    let client_ips: Arc<Mutex<HashSet<IpAddr>>> = Arc::new(Mutex::new(HashSet::new()));
+    // users directory, every user with where it logged in last
+    let accounts: Arc<Mutex<Accounts>> = Arc::new(Mutex::new(Accounts::load(&format!(
+        "accounts_server_{}.json",
+        server_num
+    ))));
 
     // create a channel to communicate between the receiving thread and the main thread
     let (tx, rx): (Sender<String>, Receiver<String>) = mpsc::channel();
@@ -234,31 +510,129 @@ fn main() {
     let data_arc = Arc::clone(&client_data);
     let tx_clone = mpsc::Sender::clone(&tx);
     let rec_socket = socket3.try_clone().unwrap();
+    let resend_socket = socket4.try_clone().unwrap();
+
+    // last encoded image and key sent to every client, in case it asks for it again.
+    // they are only kept until the client says it has them
+    let last_encoded = Arc::new(Mutex::new(HashMap::<SocketAddr, (Vec<u8>, Vec<u8>)>::new()));
+    let last_encoded_arc = Arc::clone(&last_encoded);
 
     /////////////////////////////////////////////////////////////////
     /// thread to receive image data from clients
-    let client_ips_arc = Arc::clone(&client_ips);
+    let accounts_arc = Arc::clone(&accounts);
+    // groups of users that owners send images to at once
+    let groups: Arc<Mutex<Groups>> = Arc::new(Mutex::new(Groups::load(&format!(
+        "groups_server_{}.json",
+        server_num
+    ))));
+    let groups_arc = Arc::clone(&groups);
 
-    // vector of message type to store the messages with mutex lock
-    let offline_clients: Arc<Mutex<Vec<MessageType>>> = Arc::new(Mutex::new(Vec::new()));
+    // view changes for clients that are offline, they survive a restart of the server
+    let offline_store = OfflineStore::open(&format!("pending_server_{}.log", server_num));
+    if !offline_store.is_empty() {
+        println!(
+            "{} pending updates for offline clients",
+            offline_store.len()
+        );
+    }
+    let offline_clients: Arc<Mutex<OfflineStore>> = Arc::new(Mutex::new(offline_store));
     let offline_clients_arc = Arc::clone(&offline_clients);
 
+    // codec and cover every client asked for with its upload
+    let client_options: Arc<Mutex<HashMap<String, UploadOptions>>> =
+        Arc::new(Mutex::new(HashMap::new()));
+    let client_options_arc = Arc::clone(&client_options);
+    // covers uploaded along with an image
+    let client_covers: Arc<Mutex<HashMap<String, Vec<u8>>>> = Arc::new(Mutex::new(HashMap::new()));
+    let client_covers_arc = Arc::clone(&client_covers);
+    // what every client searched the directory for
+    let directory_queries: Arc<Mutex<HashMap<String, DirectoryQuery>>> =
+        Arc::new(Mutex::new(HashMap::new()));
+    let directory_queries_arc = Arc::clone(&directory_queries);
+    // the codec and bits every client wants to see the gallery's capacity for
+    let gallery_codecs: Arc<Mutex<HashMap<String, (u8, u8)>>> =
+        Arc::new(Mutex::new(HashMap::new()));
+    let gallery_codecs_arc = Arc::clone(&gallery_codecs);
+
+    // the leader of the last election, only it sends changes of the directory to subscribers
+    let current_leader: Arc<Mutex<u16>> = Arc::new(Mutex::new(0));
+    let current_leader_arc = Arc::clone(&current_leader);
+
+    // clients that stopped sending heartbeats are offline
+    let accounts_presence = Arc::clone(&accounts);
+    let current_leader_presence = Arc::clone(&current_leader);
+    let presence_socket = socket4.try_clone().unwrap();
+    thread::spawn(move || loop {
+        thread::sleep(Duration::from_secs(1));
+        let mut accounts_lock = accounts_presence.lock().unwrap();
+        for username in accounts_lock.expire(HEARTBEAT_TIMEOUT) {
+            println!("{} missed its heartbeats, marking it offline", username);
+            if *current_leader_presence.lock().unwrap() == server_num {
+                push_directory_update(&presence_socket, &accounts_lock, &username);
+            }
+        }
+    });
+
+    // images left for users, every server keeps them until the user acks them or they expire
+    let mailbox: Arc<Mutex<Mailbox>> = Arc::new(Mutex::new(Mailbox::open(&format!(
+        "mailbox_server_{}",
+        server_num
+    ))));
+    let mailbox_arc = Arc::clone(&mailbox);
+
+    let offline_clients_retry = Arc::clone(&offline_clients);
+    let accounts_retry = Arc::clone(&accounts);
+    let mailbox_retry = Arc::clone(&mailbox);
+    let current_leader_retry = Arc::clone(&current_leader);
+    let retry_socket = socket4.try_clone().unwrap();
+    // whoever is the leader now sends the updates nobody acked yet,
+    // so they still arrive if a datagram is lost or the leader changes
+    thread::spawn(move || loop {
+        thread::sleep(Duration::from_secs(RETRY_INTERVAL));
+        let expired = mailbox_retry.lock().unwrap().expire(MAIL_EXPIRY);
+        if expired > 0 {
+            println!("Dropped {} images nobody picked up", expired);
+        }
+        if *current_leader_retry.lock().unwrap() != server_num {
+            continue;
+        }
+        let mut mails = Vec::new();
+        {
+            let offline_lock = offline_clients_retry.lock().unwrap();
+            let accounts_lock = accounts_retry.lock().unwrap();
+            for recipient in offline_lock.recipients() {
+                deliver_pending(&retry_socket, &offline_lock, &accounts_lock, &recipient);
+            }
+            let mut mailbox_lock = mailbox_retry.lock().unwrap();
+            for (username, addr) in accounts_lock.subscribed() {
+                for mail in mailbox_lock.due(&username, MAIL_RETRY_INTERVAL) {
+                    mails.push((addr, mail));
+                }
+            }
+        }
+        // images take a while to send, nothing is locked meanwhile
+        for (addr, mail) in mails {
+            println!(
+                "----- SENDING IMAGE {} FROM {} TO {} -----",
+                mail.header.image, mail.from, mail.header.to
+            );
+            deliver_mail(&retry_socket, addr, &mail);
+        }
+    });
+
     thread::spawn(move || {
         let mut buffer = [0; 65535];
         let mut src_client;
+        // images for other users that are still arriving, by who sends them
+        let mut mail_uploads: HashMap<String, Vec<u8>> = HashMap::new();
         loop {
             // recieve a fragment from any client
-            let (amt, src) = rec_socket
-                .recv_from(&mut buffer)
+            // the key it is signed with says which user sent it, along with the session
+            let (amt, src, public_key) = rec_socket
+                .recv_from_key(&mut buffer)
                 .expect("Didn't receive data");
 
-            // get the ip of the cient and insert into DOS if first time to send
             let client_ip = src.ip();
-            let mut client_ips_lock = client_ips_arc.lock().unwrap();
-            if client_ips_lock.insert(client_ip) {
-                println!("New client connected with IP: {}", client_ip);
-            }
-
             let sending_client = src.to_string();
             let msg = str::from_utf8(&buffer[..amt]).unwrap();
 
@@ -268,56 +642,38 @@ fn main() {
                 Ok(message) => {
                     println!("THIS IS MESSAGE TYPE");
                     println!("Received: {:?} from {}", message, src);
-                    // check if the message has offline, if so, add to the vector of messages
-                    if message.id == 111 {
-                        // add the message to the vector of messages
-                        println!("THIS IS OFFLINE MESSAGE");
-                        let mut client_messages_lock = offline_clients_arc.lock().unwrap();
-                        // make sure that this is the only offline message from the client
-                        let mut flag = true;
-                        for i in 0..client_messages_lock.len() {
-                            if client_messages_lock[i].message == message.message {
-                                flag = false;
-                                break;
-                            }
-                        }
-                        if flag {
-                            client_messages_lock.push(message);
-                        }
-                        continue;
-                    }
-                    // send to the client stored before the online message
+                    // a change of views for a client that is offline, kept until it joins again
                     if message.id == 5 {
-                        // loop over the vector of messages and find the message with the same ip as the message
-                        // set the number of views to the one in the message, and the name to the one in the message
-                        let mut client_messages_lock = offline_clients_arc.lock().unwrap();
-                        for i in 0..client_messages_lock.len() {
-                            if client_messages_lock[i].message == message.message {
-                                client_messages_lock[i].name = message.name;
-                                client_messages_lock[i].views = message.views;
-                                println!("THIS IS ONLINE MESSAGE");
-                                break;
+                        // only a logged in owner leaves changes, it is who the image is from
+                        let owner = accounts_arc
+                            .lock()
+                            .unwrap()
+                            .user_in(&message.session, &public_key)
+                            .map(|account| account.username.clone());
+                        let owner = match owner {
+                            Some(owner) => owner,
+                            None => {
+                                println!(
+                                    "Dropped a view change from {} that is not logged in",
+                                    src
+                                );
+                                continue;
                             }
-                        }
+                        };
+                        // it replaces what was kept for the same image of the client before
+                        offline_clients_arc.lock().unwrap().put(PendingUpdate {
+                            recipient: message.message,
+                            owner,
+                            image: message.name,
+                            views: message.views,
+                            // the owner's signature goes along so the client can check it
+                            signature: message.signature,
+                            revoke: message.revoke,
+                            issued: message.issued,
+                        });
+                        println!("THIS IS OFFLINE MESSAGE");
                         continue;
                     }
-
-                    if message.id == 222 {
-                        println!("THIS IS NEW ONLINE MESSAGE");
-                        println!("Received: {:?} from {}", message, src);
-                        // check for src with port 9999 is in the vector of messages
-                        let mut src_x = src.to_string();
-                        src_x.truncate(src_x.len() - 5);
-                        src_x.push_str(":9999");
-                        let mut client_messages_lock = offline_clients_arc.lock().unwrap();
-                        for i in 0..client_messages_lock.len() {
-                            if client_messages_lock[i].message == src_x {
-                                let msg = "update:".to_string() + i.to_string().as_str();
-                                tx_clone.send(msg).unwrap();
-                                break;
-                            }
-                        }
-                    }
                 }
                 Err(_) => {
                     // println!("THIS IS NOT MESSAGE TYPE");
@@ -325,18 +681,462 @@ fn main() {
                     // the chunk and the request type that will be used to differentiate between requests.
                     let recieved_chunk = &image_fragment.fragment;
                     let request_type = image_fragment.request_type;
+                    // clients only get into the directory by logging in, the session says who it is
+                    let session = image_fragment.session.clone();
+                    let back = accounts_arc
+                        .lock()
+                        .unwrap()
+                        .seen(&session, &public_key, client_ip);
+                    let sender = accounts_arc
+                        .lock()
+                        .unwrap()
+                        .user_in(&session, &public_key)
+                        .map(|account| account.username.clone());
+                    // everyone hears it is back, leave and join tell them themselves
+                    let leading = *current_leader_arc.lock().unwrap() == server_num;
+                    if let Some(username) = sender.as_ref().filter(|_| {
+                        back && leading
+                            && request_type != request_type_leave
+                            && request_type != request_type_join
+                    }) {
+                        println!("{} is back", username);
+                        push_directory_update(
+                            &resend_socket,
+                            &accounts_arc.lock().unwrap(),
+                            username,
+                        );
+                    }
 
                     // if the request type is directory, send to main process ip_dirrr and continue
                     if request_type == request_type_directory {
+                        // an empty fragment asks for everyone
+                        directory_queries_arc.lock().unwrap().insert(
+                            src.to_string(),
+                            serde_json::from_slice(recieved_chunk).unwrap_or_default(),
+                        );
                         src_client = src.to_string();
                         src_client.push_str("_dirrr");
                         tx_clone.send(src_client).unwrap();
                         continue;
                     }
+                    // the client got a corrupted encoded image, only the server that encoded it has it
+                    if request_type == request_type_resend {
+                        if let Some((png, key)) = last_encoded_arc.lock().unwrap().get(&src) {
+                            let temp = format!("{}:{}", client_ip, port3);
+                            println!("----- RESENDING IMAGE TO CLIENT WITH IP: {} -----", temp);
+                            send_encoded_image(
+                                &resend_socket,
+                                &temp,
+                                png,
+                                key,
+                                request_type_image,
+                                request_type_key,
+                            );
+                        }
+                        continue;
+                    }
+                    if request_type == request_type_encoded_ack {
+                        last_encoded_arc.lock().unwrap().remove(&src);
+                        continue;
+                    }
+                    // seen above is all a heartbeat needs
+                    if request_type == request_type_heartbeat {
+                        continue;
+                    }
+                    if request_type == request_type_relay {
+                        if *current_leader_arc.lock().unwrap() != server_num {
+                            continue;
+                        }
+                        let relayed: Relayed = match serde_json::from_slice(recieved_chunk) {
+                            Ok(relayed) => relayed,
+                            Err(_) => continue,
+                        };
+                        // it goes where the peer listens for what we push to it
+                        let to = accounts_arc
+                            .lock()
+                            .unwrap()
+                            .get(&relayed.to)
+                            .filter(|account| account.online)
+                            .and_then(|account| {
+                                Some(SocketAddr::new(account.endpoint?, account.subscribed?))
+                            });
+                        match (sender.clone(), to) {
+                            (Some(from), Some(to)) => {
+                                let message = MessageType {
+                                    message: from,
+                                    id: 17,
+                                    image_fragment: relayed.message.into_bytes(),
+                                    ..Default::default()
+                                };
+                                let encoded = serde_json::to_string(&message).unwrap();
+                                resend_socket
+                                    .send_to(encoded.as_bytes(), to)
+                                    .expect("Failed to send data to client");
+                            }
+                            _ => println!(
+                                "Can not pass a message from {} on to {}",
+                                client_ip, relayed.to
+                            ),
+                        }
+                        continue;
+                    }
+                    if request_type == request_type_mail {
+                        let upload = mail_uploads.entry(sending_client).or_default();
+                        // more than a mailbox holds is never kept
+                        if upload.len() + recieved_chunk.len() <= MAILBOX_QUOTA {
+                            upload.extend_from_slice(recieved_chunk);
+                        }
+                        continue;
+                    }
+                    if request_type == request_type_mail_end {
+                        let bytes = mail_uploads.remove(&sending_client).unwrap_or_default();
+                        let header: MailHeader =
+                            serde_json::from_slice(recieved_chunk).unwrap_or_default();
+                        let recipient_exists =
+                            accounts_arc.lock().unwrap().get(&header.to).is_some();
+                        let to = header.to.clone();
+                        let result = match sender {
+                            None => Err("log in first".to_string()),
+                            Some(_) if !recipient_exists => Err(format!("there is no user {}", to)),
+                            Some(_) if !integrity::verify(&bytes, &header.checksum) => {
+                                Err("the image arrived corrupted".to_string())
+                            }
+                            Some(from) => mailbox_arc.lock().unwrap().put(&from, header, bytes),
+                        };
+                        let reply = match result {
+                            Ok(()) => {
+                                println!("Keeping an image from {} for {}", client_ip, to);
+                                ImageFragment {
+                                    fragment: b"OK".to_vec(),
+                                    request_type,
+                                    ..Default::default()
+                                }
+                            }
+                            Err(e) => {
+                                println!("Not keeping an image from {}: {}", client_ip, e);
+                                ImageFragment {
+                                    fragment: e.into_bytes(),
+                                    request_type: request_type_error,
+                                    ..Default::default()
+                                }
+                            }
+                        };
+                        // only the leader answers
+                        if *current_leader_arc.lock().unwrap() == server_num {
+                            let encoded = serde_json::to_string(&reply).unwrap();
+                            let temp = format!("{}:{}", client_ip, port3);
+                            resend_socket
+                                .send_to(encoded.as_bytes(), &temp)
+                                .expect("Failed to send data to client");
+                        }
+                        continue;
+                    }
+                    if request_type == request_type_group_mail {
+                        let bytes = mail_uploads.remove(&sending_client).unwrap_or_default();
+                        let header: GroupMailHeader =
+                            serde_json::from_slice(recieved_chunk).unwrap_or_default();
+                        let group = sender.as_ref().and_then(|from| {
+                            groups_arc
+                                .lock()
+                                .unwrap()
+                                .owned_by(from)
+                                .into_iter()
+                                .find(|group| group.name == header.group)
+                        });
+                        let result = match (&sender, group) {
+                            (None, _) => Err("log in first".to_string()),
+                            (_, None) => Err(format!("you have no group {}", header.group)),
+                            _ if !integrity::verify(&bytes, &header.checksum) => {
+                                Err("the image arrived corrupted".to_string())
+                            }
+                            (Some(from), Some(group)) => {
+                                // every member gets its own number, so its views are its own
+                                let mut kept: BTreeMap<String, Result<(i32, i32), String>> =
+                                    BTreeMap::new();
+                                let members =
+                                    group.members.iter().filter(|(member, _)| *member != from);
+                                let mut mailbox = mailbox_arc.lock().unwrap();
+                                for (i, (member, views)) in members.enumerate() {
+                                    let image = header.first_image + i as i32;
+                                    let mail = MailHeader {
+                                        to: member.clone(),
+                                        image,
+                                        views: *views,
+                                        checksum: header.checksum.clone(),
+                                    };
+                                    let result = mailbox
+                                        .put(from, mail, bytes.clone())
+                                        .map(|()| (image, *views));
+                                    kept.insert(member.clone(), result);
+                                }
+                                Ok(kept)
+                            }
+                        };
+                        let reply = match result {
+                            Ok(kept) => {
+                                println!(
+                                    "Keeping an image from {} for the {} members of {}",
+                                    client_ip,
+                                    kept.len(),
+                                    header.group
+                                );
+                                ImageFragment {
+                                    fragment: serde_json::to_vec(&kept).unwrap(),
+                                    request_type,
+                                    ..Default::default()
+                                }
+                            }
+                            Err(e) => {
+                                println!("Not keeping an image from {}: {}", client_ip, e);
+                                ImageFragment {
+                                    fragment: e.into_bytes(),
+                                    request_type: request_type_error,
+                                    ..Default::default()
+                                }
+                            }
+                        };
+                        // only the leader answers
+                        if *current_leader_arc.lock().unwrap() == server_num {
+                            let encoded = serde_json::to_string(&reply).unwrap();
+                            let temp = format!("{}:{}", client_ip, port3);
+                            resend_socket
+                                .send_to(encoded.as_bytes(), &temp)
+                                .expect("Failed to send data to client");
+                        }
+                        continue;
+                    }
+                    if request_type == request_type_group {
+                        // every server changes its groups, the clients send to all of them
+                        let request: Option<GroupRequest> =
+                            serde_json::from_slice(recieved_chunk).ok();
+                        let result = {
+                            let accounts_lock = accounts_arc.lock().unwrap();
+                            match (accounts_lock.user_in(&session, &public_key), request) {
+                                (None, _) => Err("log in first".to_string()),
+                                (_, None) => Err("not a group request".to_string()),
+                                (Some(account), Some(request)) => groups_arc.lock().unwrap().apply(
+                                    &account.username,
+                                    request,
+                                    |member| accounts_lock.get(member).is_some(),
+                                ),
+                            }
+                        };
+                        let reply = match result {
+                            Ok(groups) => ImageFragment {
+                                fragment: serde_json::to_vec(&groups).unwrap(),
+                                request_type,
+                                ..Default::default()
+                            },
+                            Err(e) => {
+                                println!("Group request of {} refused: {}", client_ip, e);
+                                ImageFragment {
+                                    fragment: e.into_bytes(),
+                                    request_type: request_type_error,
+                                    ..Default::default()
+                                }
+                            }
+                        };
+                        // only the leader answers
+                        if *current_leader_arc.lock().unwrap() == server_num {
+                            let encoded = serde_json::to_string(&reply).unwrap();
+                            let temp = format!("{}:{}", client_ip, port3);
+                            resend_socket
+                                .send_to(encoded.as_bytes(), &temp)
+                                .expect("Failed to send data to client");
+                        }
+                        continue;
+                    }
+                    if request_type == request_type_mail_ack {
+                        let (from, image): (String, i32) =
+                            match serde_json::from_slice(recieved_chunk) {
+                                Ok(ack) => ack,
+                                Err(_) => continue,
+                            };
+                        if let Some(to) = sender {
+                            mailbox_arc.lock().unwrap().ack(&to, &from, image);
+                        }
+                        continue;
+                    }
+                    // every server forgets the update once the client has it
+                    if request_type == request_type_ack {
+                        let (owner, image, views): (String, String, i32) =
+                            match serde_json::from_slice(recieved_chunk) {
+                                Ok(ack) => ack,
+                                Err(_) => continue,
+                            };
+                        if let Some(recipient) = sender {
+                            offline_clients_arc
+                                .lock()
+                                .unwrap()
+                                .ack(&recipient, &owner, &image, views);
+                        }
+                        continue;
+                    }
+                    if request_type == request_type_subscribe {
+                        let port: u16 = serde_json::from_slice(recieved_chunk).unwrap_or(port3);
+                        println!(
+                            "Client with IP: {} subscribed to the directory on port {}",
+                            client_ip, port
+                        );
+                        accounts_arc
+                            .lock()
+                            .unwrap()
+                            .subscribe(&session, &public_key, port);
+                        continue;
+                    }
+                    if request_type == request_type_leave || request_type == request_type_join {
+                        let online = request_type == request_type_join;
+                        let mut accounts_lock = accounts_arc.lock().unwrap();
+                        accounts_lock.set_online(&session, &public_key, online);
+                        let leading = *current_leader_arc.lock().unwrap() == server_num;
+                        let username = match sender {
+                            Some(username) => username,
+                            None => continue,
+                        };
+                        if leading {
+                            push_directory_update(&resend_socket, &accounts_lock, &username);
+                        }
+                        if !online {
+                            println!("Client with IP: {} left", client_ip);
+                            continue;
+                        }
+                        println!("Client with IP: {} joined", client_ip);
+                        // the leader sends what was kept for it right away, the retries do the rest
+                        drop(accounts_lock);
+                        if leading {
+                            deliver_pending(
+                                &resend_socket,
+                                &offline_clients_arc.lock().unwrap(),
+                                &accounts_arc.lock().unwrap(),
+                                &username,
+                            );
+                        }
+                        continue;
+                    }
+                    // every server answers these itself, they do not wait for the leader
+                    if request_type == request_type_hello {
+                        let hello = ImageFragment {
+                            fragment: Vec::new(),
+                            request_type: request_type_hello,
+                            ..Default::default()
+                        };
+                        let encoded = serde_json::to_string(&hello).unwrap();
+                        let temp = format!("{}:{}", client_ip, port3);
+                        resend_socket
+                            .send_to(encoded.as_bytes(), &temp)
+                            .expect("Failed to send data to client");
+                        continue;
+                    }
+                    if request_type == request_type_register || request_type == request_type_login {
+                        let credentials: Credentials =
+                            serde_json::from_slice(recieved_chunk).unwrap_or_default();
+                        // the key the client signed this with is the one its user has from now on
+                        let mut accounts_lock = accounts_arc.lock().unwrap();
+                        let result = if request_type == request_type_register {
+                            accounts_lock.register(&credentials, &public_key, client_ip)
+                        } else {
+                            accounts_lock.login(&credentials, &public_key, client_ip)
+                        };
+                        let reply = match result {
+                            Ok(()) => {
+                                println!(
+                                    "{} logged in from IP: {}",
+                                    credentials.username.trim(),
+                                    client_ip
+                                );
+                                if *current_leader_arc.lock().unwrap() == server_num {
+                                    push_directory_update(
+                                        &resend_socket,
+                                        &accounts_lock,
+                                        credentials.username.trim(),
+                                    );
+                                }
+                                ImageFragment {
+                                    fragment: b"OK".to_vec(),
+                                    request_type,
+                                    ..Default::default()
+                                }
+                            }
+                            Err(e) => ImageFragment {
+                                fragment: e.into_bytes(),
+                                request_type: request_type_error,
+                                ..Default::default()
+                            },
+                        };
+                        let encoded = serde_json::to_string(&reply).unwrap();
+                        let temp = format!("{}:{}", client_ip, port3);
+                        resend_socket
+                            .send_to(encoded.as_bytes(), &temp)
+                            .expect("Failed to send data to client");
+                        continue;
+                    }
+                    // same for the cover gallery with ip_covers
+                    if request_type == request_type_gallery {
+                        gallery_codecs_arc.lock().unwrap().insert(
+                            src.to_string(),
+                            serde_json::from_slice(recieved_chunk).unwrap_or_default(),
+                        );
+                        src_client = src.to_string();
+                        src_client.push_str("_covers");
+                        tx_clone.send(src_client).unwrap();
+                        continue;
+                    }
+                    // the uploader's own cover is kept apart from the image
+                    if request_type == request_type_cover {
+                        client_covers_arc
+                            .lock()
+                            .unwrap()
+                            .entry(sending_client)
+                            .or_default()
+                            .extend_from_slice(recieved_chunk);
+                        continue;
+                    }
 
                     if recieved_chunk == b"MINSENDEND" {
                         // println!("Finished receiving image from client: {}", src.to_string());
                         src_client = src.to_string();
+                        // the checksum covers the uploaded cover and then the image
+                        let mut upload = client_covers_arc
+                            .lock()
+                            .unwrap()
+                            .get(&src_client)
+                            .cloned()
+                            .unwrap_or_default();
+                        upload.extend_from_slice(
+                            data_arc
+                                .lock()
+                                .unwrap()
+                                .get(&src_client)
+                                .map(|data| data.as_slice())
+                                .unwrap_or_default(),
+                        );
+                        if !integrity::verify(&upload, &image_fragment.checksum) {
+                            // drop what arrived and ask the client for all of it again
+                            println!("Corrupted upload from client: {}", src_client);
+                            data_arc.lock().unwrap().remove(&src_client);
+                            client_covers_arc.lock().unwrap().remove(&src_client);
+                            let resend_message = ImageFragment {
+                                fragment: Vec::new(),
+                                request_type: request_type_resend,
+                                ..Default::default()
+                            };
+                            let encoded = serde_json::to_string(&resend_message).unwrap();
+                            let temp = format!("{}:{}", client_ip, port3);
+                            resend_socket
+                                .send_to(encoded.as_bytes(), &temp)
+                                .expect("Failed to send data to client");
+                            continue;
+                        }
+                        client_options_arc.lock().unwrap().insert(
+                            src_client.clone(),
+                            UploadOptions {
+                                codec: image_fragment.codec,
+                                codec_bits: image_fragment.codec_bits,
+                                cover_id: image_fragment.cover_id,
+                                metadata: image_fragment.metadata,
+                                owner: sender,
+                            },
+                        );
                         tx_clone.send(src_client).unwrap();
                         continue;
                     }
@@ -362,7 +1162,23 @@ fn main() {
     let mut election_starter = 1;
     let mut die_message_counter = 0;
 
-    let default_image = file_as_dynamic_image("default.png".to_string());
+    // pool of cover images, the smallest one the payload fits in is used.
+    // it is also the gallery uploaders can pick from, numbered from 1
+    let mut cover_pool = Vec::new();
+    let mut cover_names = Vec::new();
+    for path in ["default.png", "default2.png", "default.jpg"] {
+        match image::open(path) {
+            Ok(cover) => {
+                cover_pool.push(cover.to_rgba8());
+                cover_names.push(path.to_string());
+            }
+            Err(e) => println!("Could not load cover image {}: {}", path, e),
+        }
+    }
+    // scale up the largest cover when a payload does not fit in any of them
+    let upscale_covers = std::env::args().any(|arg| arg == "--upscale");
+    // also save every encoded image to a file
+    let export_encoded = std::env::args().any(|arg| arg == "--export");
     // send from server to another server
     thread::sleep(Duration::from_secs(3));
     loop {
@@ -383,68 +1199,72 @@ fn main() {
             mem_usage += 2.0;
             println!("** SERVER {} IS THE LEADER **", server_num);
         }
+        *current_leader.lock().unwrap() = leader;
 
         //vector of bytes to store the image
         let mut src_client = rx.recv().unwrap();
 
-        if src_client.contains("update") {
-            let mut client_messages_lock = offline_clients.lock().unwrap();
-            let mut index = src_client.split(":").collect::<Vec<&str>>()[1].to_string();
-            let index = index.parse::<usize>().unwrap();
-            let message = MessageType {
-                message: client_messages_lock[index].message.clone(),
-                id: 10,
-                image_fragment: client_messages_lock[index].image_fragment.clone(),
-                views: client_messages_lock[index].views,
-                name: client_messages_lock[index].name.clone(),
-                is_sample: client_messages_lock[index].is_sample,
-                sample_num: client_messages_lock[index].sample_num,
-            };
-            let encoded = serde_json::to_string(&message).unwrap();
-            if server_num == leader {
-                println!(
-                    "----- SENDING ONLINE MESSAGE TO CLIENT WITH IP: {} -----",
-                    message.message
-                );
-                socket4
-                    .send_to(encoded.as_bytes(), &message.message)
-                    .expect("Failed to send data to client");
-                client_messages_lock.remove(index);
-            }
-            message_counter += 1;
-            election_starter = leader;
-            if (mem_usage >= 1000.0) {
-                let flag: bool = (message_counter != die_message_counter + 1)
-                    && (message_counter != die_message_counter + 2)
-                    && (message_counter != die_message_counter + 3);
-                if (message_counter % 4 == 0) && (message_counter != 0) && flag {
-                    println!("----- RELOADING THIS SERVER -----");
-                    // change the memory usage of a random server
-                    mem_usage -= 1000.0;
-                }
-            }
-            continue;
-        }
-
         // if the src_client has _dirrr, then it is a directory request
-        if src_client.contains("_dirrr") {
+        // _covers is the same but for the cover gallery
+        if src_client.contains("_dirrr") || src_client.contains("_covers") {
+            let wants_gallery = src_client.contains("_covers");
             // get the src of the client to send directory to.
             src_client = src_client.split("_dirrr").collect::<Vec<&str>>()[0].to_string();
+            src_client = src_client.split("_covers").collect::<Vec<&str>>()[0].to_string();
+            // what the client asked for is kept by the address it asked from
+            let query = directory_queries
+                .lock()
+                .unwrap()
+                .remove(&src_client)
+                .unwrap_or_default();
+            let (codec, codec_bits) = gallery_codecs
+                .lock()
+                .unwrap()
+                .remove(&src_client)
+                .unwrap_or_default();
             src_client = src_client.split(":").collect::<Vec<&str>>()[0].to_string();
-            if server_num == leader {
+            if server_num == leader && wants_gallery {
                 let temp = format!("{}:{}", src_client, ports[3]);
-                println!("----- SENDING DIRECTORY TO CLIENT WITH IP: {} -----", temp);
-                // unlock the mutex and send the directory to the client
-                let mut directory = String::new();
-                let client_ips_lock = client_ips.lock().unwrap();
-                for ip in client_ips_lock.iter() {
-                    directory.push_str(&ip.to_string());
-                    directory.push_str("\n");
+                println!(
+                    "----- SENDING COVER GALLERY TO CLIENT WITH IP: {} -----",
+                    temp
+                );
+                // one line per cover with its number, size and how much it can hold
+                // with the codec the client picked, the alpha channel if it did not pick one
+                let codec: Box<dyn StegoCodec + Send> =
+                    stego::codec_from_id(codec, codec_bits).unwrap_or(Box::new(AlphaCodec));
+                let mut gallery = String::new();
+                for i in 0..cover_pool.len() {
+                    let (width, height) = cover_pool[i].dimensions();
+                    gallery.push_str(&format!(
+                        "{}: {} ({}x{}, holds {} bytes)\n",
+                        i + 1,
+                        cover_names[i],
+                        width,
+                        height,
+                        stego::capacity(codec.as_ref(), width, height)
+                    ));
                 }
+                let image_fragment = ImageFragment {
+                    fragment: gallery.as_bytes().to_vec(),
+                    request_type: request_type_gallery,
+                    ..Default::default()
+                };
+                let encoded = serde_json::to_string(&image_fragment).unwrap();
+                socket4
+                    .send_to(encoded.as_bytes(), &temp)
+                    .expect("Failed to send data to client");
+            } else if server_num == leader {
+                let temp = format!("{}:{}", src_client, ports[3]);
+                println!("----- SENDING DIRECTORY TO CLIENT WITH IP: {} -----", temp);
+                // the users that match what the client searched for, with where they are,
+                // if they are online and the key they sign with
+                let directory = accounts.lock().unwrap().search(&query);
                 // create the fragment with the directory and send it to the client only if leader.
                 let image_fragment = ImageFragment {
-                    fragment: directory.as_bytes().to_vec(),
+                    fragment: serde_json::to_vec(&directory).unwrap(),
                     request_type: request_type_directory,
+                    ..Default::default()
                 };
                 let encoded = serde_json::to_string(&image_fragment).unwrap();
                 socket4
@@ -481,6 +1301,25 @@ fn main() {
         //remove it from the hashmap
         // client_data.remove(&src_client);
         client_data.lock().unwrap().remove(&src_client);
+        let options = client_options
+            .lock()
+            .unwrap()
+            .remove(&src_client)
+            .unwrap_or(UploadOptions {
+                codec: 0,
+                codec_bits: 0,
+                cover_id: 0,
+                metadata: None,
+                owner: None,
+            });
+        let uploaded_cover = client_covers.lock().unwrap().remove(&src_client);
+        if let Some(owner) = &options.owner {
+            let mut accounts_lock = accounts.lock().unwrap();
+            accounts_lock.count_image(owner);
+            if server_num == leader {
+                push_directory_update(&socket4, &accounts_lock, owner);
+            }
+        }
 
         // reconstruct the image from the fragments
         let mut reconstructed_image_bytes = Vec::new();
@@ -488,61 +1327,101 @@ fn main() {
             reconstructed_image_bytes.push(image_from_client[j]);
         }
         // let decoded_image = base64::decode(reconstructed_image_bytes).unwrap();
-        let path = format!("decoded_image_message_{}.png", message_counter);
-        let mut file = File::create(path).unwrap();
-        file.write_all(&reconstructed_image_bytes);
 
         // send from server to client
         if server_num == leader {
-            // encode the recieved picture into the default picture
-            let msg_bytes = &reconstructed_image_bytes.as_slice();
-            let msg_bytes_base64 = base64::encode(msg_bytes);
-            let bytes_to_send = msg_bytes_base64.as_bytes();
-            let enc = Encoder::new(bytes_to_send, default_image.clone());
-            let result = enc.encode_alpha();
-            let path = format!("hidden_message_{}.png", message_counter);
-            save_image_buffer(result, path.clone());
-
-            // convert the result to base64
-            let mut payload = File::open(path).unwrap();
-            let mut payload_bytes = Vec::new();
-            payload.read_to_end(&mut payload_bytes).unwrap();
-
-            let mut fragmented_payload = Vec::new();
-            for chunk in payload_bytes.chunks(1024) {
-                fragmented_payload.push(chunk);
-            }
+            // encrypt the recieved picture with a new key, then encode it into the default picture.
+            // the key only goes back to the owner, the server does not keep it
+            let image_key = crypto::generate_key();
+            let sealed = crypto::seal(&image_key, &reconstructed_image_bytes);
+            // the owner's metadata goes in front of the encrypted image, it has to be
+            // signed by the owner and about this very image
+            let payload = match &options.metadata {
+                Some(image_metadata)
+                    if !image_metadata.verify()
+                        || !integrity::verify(
+                            &reconstructed_image_bytes,
+                            &image_metadata.content_hash,
+                        ) =>
+                {
+                    Err("the image metadata is not signed by its owner".to_string())
+                }
+                Some(image_metadata) => Ok(metadata::pack(image_metadata, &sealed)),
+                None => Ok(sealed),
+            };
+            // use the codec the client asked for, the alpha channel if it did not ask
+            let codec: Box<dyn StegoCodec + Send> =
+                match stego::codec_from_id(options.codec, options.codec_bits) {
+                    Some(codec) => codec,
+                    None => Box::new(AlphaCodec),
+                };
+            // the uploader's own cover, then one it picked from the gallery, then the whole pool
+            let covers: Result<Cow<[RgbaImage]>, String> = match uploaded_cover {
+                Some(cover) => image::load_from_memory(&cover)
+                    .map(|cover| Cow::Owned(vec![cover.to_rgba8()]))
+                    .map_err(|e| format!("could not read the uploaded cover: {}", e)),
+                None if options.cover_id != 0 => {
+                    match cover_pool.get(options.cover_id as usize - 1) {
+                        Some(cover) => Ok(Cow::Borrowed(std::slice::from_ref(cover))),
+                        None => Err(format!("there is no cover number {}", options.cover_id)),
+                    }
+                }
+                None => Ok(Cow::Borrowed(&cover_pool)),
+            };
+            let encoded_cover = payload.and_then(|payload| {
+                covers
+                    .and_then(|covers| {
+                        stego::fit_cover(codec.as_ref(), &covers, payload.len(), upscale_covers)
+                    })
+                    .and_then(|cover| stego::encode(codec.as_ref(), &cover, &payload))
+            });
 
-            // send to client the encoded image.
+            // several clients can be at one ip, only the address tells them apart
+            let client_addr = src_client.parse::<SocketAddr>();
             src_client = src_client.split(":").collect::<Vec<&str>>()[0].to_string();
             let temp = format!("{}:{}", src_client, ports[3]);
+            match encoded_cover {
+                Err(e) => {
+                    // tell the client instead of sending back a truncated image
+                    println!("----- COULD NOT ENCODE IMAGE FOR {}: {} -----", temp, e);
+                    let error_message = ImageFragment {
+                        fragment: e.as_bytes().to_vec(),
+                        request_type: request_type_error,
+                        ..Default::default()
+                    };
+                    let encoded = serde_json::to_string(&error_message).unwrap();
+                    socket4
+                        .send_to(encoded.as_bytes(), &temp)
+                        .expect("Failed to send data to client");
+                }
+                Ok(result) => {
+                    // the encoded image never touches the disk unless asked to export it
+                    let payload_bytes = stego::to_png(&result);
+                    if export_encoded {
+                        let path = format!("hidden_message_{}.png", message_counter);
+                        result.save(path.clone()).unwrap();
+                        println!("----- EXPORTED ENCODED IMAGE TO {} -----", path);
+                    }
 
-            for j in 0..fragmented_payload.len() {
-                // send an image fragment
-                let image_fragment = ImageFragment {
-                    fragment: fragmented_payload[j].to_vec(),
-                    request_type: request_type_image,
-                };
-                let encoded = serde_json::to_string(&image_fragment).unwrap();
-                socket4
-                    .send_to(encoded.as_bytes(), &temp)
-                    .expect("Failed to send data to client");
-
-                if j % 15 == 0 {
-                    thread::sleep(Duration::from_millis(20));
+                    // send to client the encoded image.
+                    send_encoded_image(
+                        &socket4,
+                        &temp,
+                        &payload_bytes,
+                        &image_key,
+                        request_type_image,
+                        request_type_key,
+                    );
+                    // keep it in case it arrives corrupted, until the client acks it
+                    if let Ok(addr) = client_addr {
+                        last_encoded
+                            .lock()
+                            .unwrap()
+                            .insert(addr, (payload_bytes, image_key));
+                    }
+                    println!("----- SENDING IMAGE TO CLIENT WITH IP: {} -----", temp);
                 }
             }
-            // send end to client
-            let end_message = "MINSENDEND";
-            let final_message = ImageFragment {
-                fragment: end_message.as_bytes().to_vec(),
-                request_type: request_type_image,
-            };
-            let encoded = serde_json::to_string(&final_message).unwrap();
-            socket4
-                .send_to(encoded.as_bytes(), &temp)
-                .expect("Failed to send data to client");
-            println!("----- SENDING IMAGE TO CLIENT WITH IP: {} -----", temp);
         }
 
         election_starter = leader;
@@ -0,0 +1,131 @@
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs;
+
+// users an owner sends an image to at once, every member with the views it gets
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Group {
+    pub name: String,
+    pub owner: String,
+    pub members: BTreeMap<String, i32>,
+}
+
+// what a client asks the servers to do with its groups, they answer with all of its groups
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub enum GroupRequest {
+    Create {
+        name: String,
+    },
+    Delete {
+        name: String,
+    },
+    // adding a member again changes its views
+    Add {
+        name: String,
+        member: String,
+        views: i32,
+    },
+    Remove {
+        name: String,
+        member: String,
+    },
+    List,
+}
+
+// every group the server knows, saved to a file whenever one changes.
+// group names only have to be unique for their owner
+pub struct Groups {
+    path: String,
+    groups: Vec<Group>,
+}
+
+impl Groups {
+    pub fn load(path: &str) -> Groups {
+        let groups = fs::read(path)
+            .ok()
+            .and_then(|bytes| serde_json::from_slice(&bytes).ok())
+            .unwrap_or_default();
+        Groups {
+            path: path.to_string(),
+            groups,
+        }
+    }
+
+    fn save(&self) {
+        if let Err(e) = fs::write(&self.path, serde_json::to_vec(&self.groups).unwrap()) {
+            println!("Could not save groups to {}: {}", self.path, e);
+        }
+    }
+
+    fn find_mut(&mut self, owner: &str, name: &str) -> Result<&mut Group, String> {
+        self.groups
+            .iter_mut()
+            .find(|group| group.owner == owner && group.name == name)
+            .ok_or_else(|| format!("you have no group {}", name))
+    }
+
+    // the groups of owner
+    pub fn owned_by(&self, owner: &str) -> Vec<Group> {
+        self.groups
+            .iter()
+            .filter(|group| group.owner == owner)
+            .cloned()
+            .collect()
+    }
+
+    // only the owner changes its groups, is_user says if a member has an account
+    pub fn apply(
+        &mut self,
+        owner: &str,
+        request: GroupRequest,
+        is_user: impl Fn(&str) -> bool,
+    ) -> Result<Vec<Group>, String> {
+        match request {
+            GroupRequest::Create { name } => {
+                let name = name.trim();
+                if name.is_empty() {
+                    return Err("a group needs a name".to_string());
+                }
+                if self.find_mut(owner, name).is_ok() {
+                    return Err(format!("you already have a group {}", name));
+                }
+                self.groups.push(Group {
+                    name: name.to_string(),
+                    owner: owner.to_string(),
+                    members: BTreeMap::new(),
+                });
+            }
+            GroupRequest::Delete { name } => {
+                self.find_mut(owner, &name)?;
+                self.groups
+                    .retain(|group| group.owner != owner || group.name != name);
+            }
+            GroupRequest::Add {
+                name,
+                member,
+                views,
+            } => {
+                if !is_user(&member) {
+                    return Err(format!("there is no user {}", member));
+                }
+                if views <= 0 {
+                    return Err("a member needs at least one view".to_string());
+                }
+                self.find_mut(owner, &name)?.members.insert(member, views);
+            }
+            GroupRequest::Remove { name, member } => {
+                if self
+                    .find_mut(owner, &name)?
+                    .members
+                    .remove(&member)
+                    .is_none()
+                {
+                    return Err(format!("{} is not in {}", member, name));
+                }
+            }
+            GroupRequest::List => return Ok(self.owned_by(owner)),
+        }
+        self.save();
+        Ok(self.owned_by(owner))
+    }
+}
@@ -0,0 +1,80 @@
+use ed25519_dalek::{Signature, Signer, SigningKey, Verifier, VerifyingKey};
+use rand::rngs::OsRng;
+use std::fs;
+use x25519_dalek::{PublicKey, StaticSecret};
+
+// ed25519 keypair of a node, kept in a file so it survives restarts
+pub struct Identity {
+    signing_key: SigningKey,
+}
+
+impl Identity {
+    // load the key from path, or make a new one and save it there
+    pub fn load_or_create(path: &str) -> Identity {
+        if let Ok(bytes) = fs::read(path) {
+            if let Ok(seed) = <[u8; 32]>::try_from(bytes.as_slice()) {
+                return Identity {
+                    signing_key: SigningKey::from_bytes(&seed),
+                };
+            }
+            println!("Identity file {} is broken, making a new one", path);
+        }
+        let signing_key = SigningKey::generate(&mut OsRng);
+        if let Err(e) = fs::write(path, signing_key.to_bytes()) {
+            println!("Could not save identity to {}: {}", path, e);
+        }
+        Identity { signing_key }
+    }
+
+    pub fn public_key(&self) -> Vec<u8> {
+        self.signing_key.verifying_key().to_bytes().to_vec()
+    }
+
+    pub fn sign(&self, bytes: &[u8]) -> Vec<u8> {
+        self.signing_key.sign(bytes).to_bytes().to_vec()
+    }
+
+    // the same keypair as an x25519 secret, to agree on session keys with other nodes
+    pub fn exchange_secret(&self) -> StaticSecret {
+        StaticSecret::from(self.signing_key.to_scalar_bytes())
+    }
+}
+
+// x25519 public key of the node that signs with public_key
+pub fn exchange_key(public_key: &[u8]) -> Option<PublicKey> {
+    let public_key = <[u8; 32]>::try_from(public_key).ok()?;
+    let key = VerifyingKey::from_bytes(&public_key).ok()?;
+    Some(PublicKey::from(key.to_montgomery().to_bytes()))
+}
+
+// check a signature made by the owner of public_key
+pub fn verify(public_key: &[u8], bytes: &[u8], signature: &[u8]) -> bool {
+    let public_key = match <[u8; 32]>::try_from(public_key) {
+        Ok(public_key) => public_key,
+        Err(_) => return false,
+    };
+    let signature = match <[u8; 64]>::try_from(signature) {
+        Ok(signature) => Signature::from_bytes(&signature),
+        Err(_) => return false,
+    };
+    match VerifyingKey::from_bytes(&public_key) {
+        Ok(key) => key.verify(bytes, &signature).is_ok(),
+        Err(_) => false,
+    }
+}
+
+pub fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+pub fn from_hex(hex: &str) -> Option<Vec<u8>> {
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+// short printable form of a public key
+pub fn fingerprint(public_key: &[u8]) -> String {
+    to_hex(&public_key[..public_key.len().min(8)])
+}
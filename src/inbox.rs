@@ -0,0 +1,149 @@
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs;
+
+// views an approved request gives when the owner does not say otherwise
+pub const DEFAULT_VIEWS: i32 = 3;
+
+// a recipient asking the owner for more views of an image
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ViewRequest {
+    // what the owner picks it by
+    pub id: u32,
+    pub from: String,
+    pub image: i32,
+}
+
+// the owner's answer, views 0 is declined
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Decision {
+    pub to: String,
+    pub image: i32,
+    pub views: i32,
+}
+
+// how requests of a user are answered without asking the owner
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum Rule {
+    // the user gets its views back up to this many
+    Approve(i32),
+    Decline,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+struct Saved {
+    next_id: u32,
+    requests: Vec<ViewRequest>,
+    rules: BTreeMap<String, Rule>,
+    // answers for users that were offline, sent when they are back
+    undelivered: Vec<Decision>,
+}
+
+// view requests the owner answers when it wants to, saved to a file so none are lost
+pub struct Inbox {
+    path: String,
+    saved: Saved,
+}
+
+impl Inbox {
+    pub fn load(path: &str) -> Inbox {
+        let saved = fs::read(path)
+            .ok()
+            .and_then(|bytes| serde_json::from_slice(&bytes).ok())
+            .unwrap_or_default();
+        Inbox {
+            path: path.to_string(),
+            saved,
+        }
+    }
+
+    fn save(&self) {
+        if let Err(e) = fs::write(&self.path, serde_json::to_vec(&self.saved).unwrap()) {
+            println!("Could not save the inbox to {}: {}", self.path, e);
+        }
+    }
+
+    // returns the answer if a rule gives one, otherwise the request waits for the owner.
+    // asking again for the same image does not queue it twice
+    pub fn request(&mut self, from: &str, image: i32) -> Option<Decision> {
+        if let Some(rule) = self.saved.rules.get(from) {
+            let views = match rule {
+                Rule::Approve(views) => *views,
+                Rule::Decline => 0,
+            };
+            return Some(Decision {
+                to: from.to_string(),
+                image,
+                views,
+            });
+        }
+        let queued = self
+            .saved
+            .requests
+            .iter()
+            .any(|request| request.from == from && request.image == image);
+        if !queued {
+            self.saved.next_id += 1;
+            self.saved.requests.push(ViewRequest {
+                id: self.saved.next_id,
+                from: from.to_string(),
+                image,
+            });
+            self.save();
+        }
+        None
+    }
+
+    // oldest first
+    pub fn requests(&self) -> &[ViewRequest] {
+        &self.saved.requests
+    }
+
+    // take a request out to answer it
+    pub fn answer(&mut self, id: u32) -> Option<ViewRequest> {
+        let index = self
+            .saved
+            .requests
+            .iter()
+            .position(|request| request.id == id)?;
+        let request = self.saved.requests.remove(index);
+        self.save();
+        Some(request)
+    }
+
+    pub fn rules(&self) -> &BTreeMap<String, Rule> {
+        &self.saved.rules
+    }
+
+    // None takes the rule of the user away
+    pub fn set_rule(&mut self, username: &str, rule: Option<Rule>) {
+        match rule {
+            Some(rule) => self.saved.rules.insert(username.to_string(), rule),
+            None => self.saved.rules.remove(username),
+        };
+        self.save();
+    }
+
+    // keep an answer until its user is online, a newer one for the same image replaces it
+    pub fn keep(&mut self, decision: Decision) {
+        self.saved
+            .undelivered
+            .retain(|kept| kept.to != decision.to || kept.image != decision.image);
+        self.saved.undelivered.push(decision);
+        self.save();
+    }
+
+    // the answers kept for a user, they are not kept any more
+    pub fn undelivered(&mut self, to: &str) -> Vec<Decision> {
+        let (kept, others) = self
+            .saved
+            .undelivered
+            .drain(..)
+            .partition(|decision| decision.to == to);
+        self.saved.undelivered = others;
+        if !kept.is_empty() {
+            self.save();
+        }
+        kept
+    }
+}
@@ -0,0 +1,11 @@
+use sha2::{Digest, Sha256};
+
+// sha-256 of a whole transfer, sent with its last fragment
+pub fn checksum(bytes: &[u8]) -> Vec<u8> {
+    Sha256::digest(bytes).to_vec()
+}
+
+// an empty expected checksum is from a sender that does not send one
+pub fn verify(bytes: &[u8], expected: &[u8]) -> bool {
+    expected.is_empty() || checksum(bytes) == expected
+}
@@ -0,0 +1,15 @@
+pub mod accounts;
+pub mod auth;
+pub mod crypto;
+pub mod groups;
+pub mod identity;
+pub mod inbox;
+pub mod integrity;
+pub mod mailbox;
+pub mod metadata;
+pub mod offline;
+pub mod peers;
+pub mod quota;
+pub mod relay;
+pub mod stego;
+pub mod transport;
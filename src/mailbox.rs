@@ -0,0 +1,173 @@
+use crate::identity;
+use crate::metadata::now;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+
+// bytes the servers keep for one recipient
+pub const MAILBOX_QUOTA: usize = 16 * 1024 * 1024;
+// seconds a mail is kept before it is dropped without being delivered
+pub const MAIL_EXPIRY: u64 = 7 * 24 * 60 * 60;
+// seconds before a mail that was not acked is sent again, images are too large to send often
+pub const MAIL_RETRY_INTERVAL: u64 = 10;
+
+// what the owner sends with the last fragment of an image for a user that may be offline
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct MailHeader {
+    // username of the recipient
+    pub to: String,
+    // the owner's number for the image, the recipient asks for views of it
+    pub image: i32,
+    pub views: i32,
+    // sha-256 of the encoded image
+    pub checksum: Vec<u8>,
+}
+
+// what the owner sends with the last fragment of an image for every member of one of its groups.
+// it is uploaded once and the servers keep a copy for every member with the views the group gives it
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct GroupMailHeader {
+    pub group: String,
+    // the owner's number for the image of the first member, the others count up from it
+    pub first_image: i32,
+    pub checksum: Vec<u8>,
+}
+
+// an encoded image kept for its recipient
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Mail {
+    // username of the owner
+    pub from: String,
+    pub header: MailHeader,
+    // unix time the servers got it
+    pub received: u64,
+    pub bytes: Vec<u8>,
+}
+
+// images for users to pick up when they are online, one file for every mail in dir.
+// a mail is known by its owner and the owner's number for the image
+pub struct Mailbox {
+    dir: String,
+    mails: HashMap<(String, i32), Mail>,
+    // when every mail was last sent, so it is not sent again right away
+    sent: HashMap<(String, i32), u64>,
+}
+
+impl Mailbox {
+    pub fn open(dir: &str) -> Mailbox {
+        if let Err(e) = fs::create_dir_all(dir) {
+            println!("Could not create mailbox {}: {}", dir, e);
+        }
+        let mut mails = HashMap::new();
+        for entry in fs::read_dir(dir).into_iter().flatten().flatten() {
+            let mail: Option<Mail> = fs::read(entry.path())
+                .ok()
+                .and_then(|bytes| serde_json::from_slice(&bytes).ok());
+            if let Some(mail) = mail {
+                mails.insert((mail.from.clone(), mail.header.image), mail);
+            }
+        }
+        Mailbox {
+            dir: dir.to_string(),
+            mails,
+            sent: HashMap::new(),
+        }
+    }
+
+    fn path(&self, from: &str, image: i32) -> String {
+        let name = format!("{}_{}.json", identity::to_hex(from.as_bytes()), image);
+        Path::new(&self.dir)
+            .join(name)
+            .to_string_lossy()
+            .to_string()
+    }
+
+    // bytes kept for a recipient
+    pub fn used(&self, to: &str) -> usize {
+        self.mails
+            .values()
+            .filter(|mail| mail.header.to == to)
+            .map(|mail| mail.bytes.len())
+            .sum()
+    }
+
+    // keep an image for header.to, the same image sent again replaces the old one
+    pub fn put(&mut self, from: &str, header: MailHeader, bytes: Vec<u8>) -> Result<(), String> {
+        let key = (from.to_string(), header.image);
+        let replaced = self
+            .mails
+            .get(&key)
+            .filter(|mail| mail.header.to == header.to)
+            .map_or(0, |mail| mail.bytes.len());
+        if self.used(&header.to) - replaced + bytes.len() > MAILBOX_QUOTA {
+            return Err(format!("the mailbox of {} is full", header.to));
+        }
+        let mail = Mail {
+            from: from.to_string(),
+            header,
+            received: now(),
+            bytes,
+        };
+        fs::write(
+            self.path(from, mail.header.image),
+            serde_json::to_vec(&mail).unwrap(),
+        )
+        .map_err(|e| format!("could not keep the image: {}", e))?;
+        self.sent.remove(&key);
+        self.mails.insert(key, mail);
+        Ok(())
+    }
+
+    // mails for a recipient that were not sent in the last retry seconds, they count as sent now
+    pub fn due(&mut self, to: &str, retry: u64) -> Vec<Mail> {
+        let now = now();
+        let mut due = Vec::new();
+        for (key, mail) in &self.mails {
+            let sent = self.sent.get(key).copied().unwrap_or(0);
+            if mail.header.to == to && now.saturating_sub(sent) >= retry {
+                due.push(mail.clone());
+            }
+        }
+        for mail in &due {
+            self.sent
+                .insert((mail.from.clone(), mail.header.image), now);
+        }
+        due
+    }
+
+    // the recipient got the image, only it can say so
+    pub fn ack(&mut self, to: &str, from: &str, image: i32) {
+        let key = (from.to_string(), image);
+        if self
+            .mails
+            .get(&key)
+            .is_some_and(|mail| mail.header.to == to)
+        {
+            self.remove(&key);
+        }
+    }
+
+    fn remove(&mut self, key: &(String, i32)) {
+        if let Err(e) = fs::remove_file(self.path(&key.0, key.1)) {
+            println!("Could not remove mail from {}: {}", self.dir, e);
+        }
+        self.mails.remove(key);
+        self.sent.remove(key);
+    }
+
+    // drop mails older than max_age seconds, returns how many
+    pub fn expire(&mut self, max_age: u64) -> usize {
+        let now = now();
+        let expired: Vec<(String, i32)> = self
+            .mails
+            .iter()
+            .filter(|(_, mail)| now.saturating_sub(mail.received) > max_age)
+            .map(|(key, _)| key.clone())
+            .collect();
+        for key in &expired {
+            self.remove(key);
+        }
+        expired.len()
+    }
+}
@@ -0,0 +1,127 @@
+use crate::identity::{self, Identity};
+use crate::integrity;
+use serde::{Deserialize, Serialize};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// marks a stego payload that starts with metadata
+const MAGIC: &[u8; 2] = b"MD";
+
+// what the owner allows recipients to do with the image
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct Policy {
+    // unix time after which the image can not be viewed
+    pub expires_at: Option<u64>,
+    // seconds after the recipient first views it
+    pub expires_after: Option<u64>,
+    // the recipient deletes the image once it was viewed
+    pub view_once: bool,
+}
+
+impl Policy {
+    // why the image can not be viewed any more, first_view is when the recipient first viewed it.
+    // the recipient's viewer enforces this, the owner only counts views
+    pub fn expired(&self, first_view: u64) -> Option<String> {
+        let now = now();
+        if let Some(expires_at) = self.expires_at.filter(|expires_at| now >= *expires_at) {
+            return Some(format!("it expired at {}", expires_at));
+        }
+        match self.expires_after {
+            Some(after) if now >= first_view.saturating_add(after) => Some(format!(
+                "it expired {} seconds after it was first viewed",
+                after
+            )),
+            _ => None,
+        }
+    }
+}
+
+// unix time in seconds
+pub fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+// signed by the owner and embedded in front of the encrypted image,
+// so anyone holding the image can check where it came from
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ImageMetadata {
+    pub owner: Vec<u8>,
+    pub image_id: u64,
+    pub created: u64,
+    // sha-256 of the original image, checked once it is decrypted
+    pub content_hash: Vec<u8>,
+    pub policy: Policy,
+    pub signature: Vec<u8>,
+}
+
+impl ImageMetadata {
+    pub fn new(
+        identity: &Identity,
+        image_id: u64,
+        content: &[u8],
+        policy: Policy,
+    ) -> ImageMetadata {
+        let created = now();
+        let mut metadata = ImageMetadata {
+            owner: identity.public_key(),
+            image_id,
+            created,
+            content_hash: integrity::checksum(content),
+            policy,
+            signature: Vec::new(),
+        };
+        metadata.signature = identity.sign(&metadata.signed_bytes());
+        metadata
+    }
+
+    // everything but the signature
+    fn signed_bytes(&self) -> Vec<u8> {
+        serde_json::to_vec(&(
+            &self.owner,
+            self.image_id,
+            self.created,
+            &self.content_hash,
+            &self.policy,
+        ))
+        .unwrap()
+    }
+
+    pub fn verify(&self) -> bool {
+        identity::verify(&self.owner, &self.signed_bytes(), &self.signature)
+    }
+}
+
+// metadata in front of the body: magic, length of the metadata, metadata, body
+pub fn pack(metadata: &ImageMetadata, body: &[u8]) -> Vec<u8> {
+    let metadata = serde_json::to_vec(metadata).unwrap();
+    let mut payload = MAGIC.to_vec();
+    payload.extend_from_slice(&(metadata.len() as u32).to_be_bytes());
+    payload.extend_from_slice(&metadata);
+    payload.extend_from_slice(body);
+    payload
+}
+
+// split a payload back up, payloads from before metadata existed have none
+pub fn unpack(payload: &[u8]) -> (Option<ImageMetadata>, &[u8]) {
+    if payload.len() < 6 || &payload[..2] != MAGIC {
+        return (None, payload);
+    }
+    let len = u32::from_be_bytes([payload[2], payload[3], payload[4], payload[5]]) as usize;
+    if payload.len() < 6 + len {
+        return (None, payload);
+    }
+    match serde_json::from_slice(&payload[6..6 + len]) {
+        Ok(metadata) => (Some(metadata), &payload[6 + len..]),
+        Err(_) => (None, payload),
+    }
+}
+
+// what the owner signs when it grants a view: the image number it sent, who to,
+// how many views are left and the key it hands out
+pub fn grant_bytes(image: i32, recipient: &str, remaining: i32, key: &[u8]) -> Vec<u8> {
+    let mut bytes = format!("grant:{}:{}:{}:", image, recipient, remaining).into_bytes();
+    bytes.extend_from_slice(&integrity::checksum(key));
+    bytes
+}
@@ -0,0 +1,306 @@
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::Write;
+
+// seconds between sending a client the updates it did not ack yet
+pub const RETRY_INTERVAL: u64 = 2;
+
+// a change of views the owner sent for a client that is offline
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct PendingUpdate {
+    // username of the client, it gets the update wherever it joins again
+    pub recipient: String,
+    // username the owner logged in with, images are numbered by their owner
+    pub owner: String,
+    pub image: String,
+    pub views: i32,
+    // the owner's signature, the client checks it and not us
+    pub signature: Vec<u8>,
+    // the owner takes the image back
+    #[serde(default)]
+    pub revoke: bool,
+    // when the owner signed it, it is part of what is signed
+    #[serde(default)]
+    pub issued: u64,
+}
+
+// one line of the log
+#[derive(Serialize, Deserialize, Debug)]
+enum Record {
+    Put(PendingUpdate),
+    // the recipient got the update for the image
+    Ack {
+        recipient: String,
+        owner: String,
+        image: String,
+        views: i32,
+    },
+}
+
+// pending updates kept in a write ahead log, so they are still there after a restart.
+// every server keeps its own, the clients send the updates to all of them
+pub struct OfflineStore {
+    path: String,
+    log: Option<File>,
+    // updates for every recipient in the order they were sent
+    pending: HashMap<String, Vec<PendingUpdate>>,
+}
+
+impl OfflineStore {
+    // replay the log, then write it again with only what is still pending
+    pub fn open(path: &str) -> OfflineStore {
+        let mut store = OfflineStore {
+            path: path.to_string(),
+            log: None,
+            pending: HashMap::new(),
+        };
+        for line in fs::read_to_string(path).unwrap_or_default().lines() {
+            // a line cut short by a crash is skipped
+            match serde_json::from_str(line) {
+                Ok(Record::Put(update)) => store.insert(update),
+                Ok(Record::Ack {
+                    recipient,
+                    owner,
+                    image,
+                    views,
+                }) => {
+                    store.remove(&recipient, &owner, &image, views);
+                }
+                Err(_) => {}
+            }
+        }
+        store.compact();
+        store
+    }
+
+    fn compact(&mut self) {
+        let temp = format!("{}.tmp", self.path);
+        let mut lines = String::new();
+        for update in self.pending.values().flatten() {
+            lines.push_str(&serde_json::to_string(&Record::Put(update.clone())).unwrap());
+            lines.push('\n');
+        }
+        if let Err(e) = fs::write(&temp, lines).and_then(|_| fs::rename(&temp, &self.path)) {
+            println!("Could not compact {}: {}", self.path, e);
+        }
+        self.log = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)
+            .map_err(|e| println!("Could not open {}: {}", self.path, e))
+            .ok();
+    }
+
+    // the record is on disk before we go on
+    fn append(&mut self, record: &Record) {
+        let mut line = serde_json::to_string(record).unwrap();
+        line.push('\n');
+        let written = match self.log.as_mut() {
+            Some(log) => log.write_all(line.as_bytes()).and_then(|_| log.sync_data()),
+            None => return,
+        };
+        if let Err(e) = written {
+            println!("Could not write to {}: {}", self.path, e);
+        }
+    }
+
+    // only the newest update for an image of an owner is kept, at the end of the queue
+    fn insert(&mut self, update: PendingUpdate) {
+        let queue = self.pending.entry(update.recipient.clone()).or_default();
+        queue.retain(|pending| pending.owner != update.owner || pending.image != update.image);
+        queue.push(update);
+    }
+
+    fn remove(&mut self, recipient: &str, owner: &str, image: &str, views: i32) -> bool {
+        let queue = match self.pending.get_mut(recipient) {
+            Some(queue) => queue,
+            None => return false,
+        };
+        let before = queue.len();
+        queue.retain(|pending| {
+            pending.owner != owner || pending.image != image || pending.views != views
+        });
+        let removed = queue.len() != before;
+        if queue.is_empty() {
+            self.pending.remove(recipient);
+        }
+        removed
+    }
+
+    pub fn put(&mut self, update: PendingUpdate) {
+        self.append(&Record::Put(update.clone()));
+        self.insert(update);
+    }
+
+    // everything kept for a recipient, it stays until the recipient acks it
+    pub fn pending(&self, recipient: &str) -> Vec<PendingUpdate> {
+        self.pending.get(recipient).cloned().unwrap_or_default()
+    }
+
+    // every recipient that has updates kept for it
+    pub fn recipients(&self) -> Vec<String> {
+        self.pending.keys().cloned().collect()
+    }
+
+    // the recipient got the update for image of owner, a newer one for the same image stays
+    pub fn ack(&mut self, recipient: &str, owner: &str, image: &str, views: i32) {
+        if self.remove(recipient, owner, image, views) {
+            self.append(&Record::Ack {
+                recipient: recipient.to_string(),
+                owner: owner.to_string(),
+                image: image.to_string(),
+                views,
+            });
+        }
+    }
+
+    // updates kept for all recipients
+    pub fn len(&self) -> usize {
+        self.pending.values().map(Vec::len).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pending.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // a log of its own for every test, they run at the same time
+    fn log_path(name: &str) -> String {
+        let path = std::env::temp_dir().join(format!(
+            "offline_{}_{}_{}.log",
+            name,
+            std::process::id(),
+            rand::random::<u32>()
+        ));
+        let _ = fs::remove_file(&path);
+        path.to_str().unwrap().to_string()
+    }
+
+    fn update(recipient: &str, owner: &str, image: &str, views: i32) -> PendingUpdate {
+        PendingUpdate {
+            recipient: recipient.to_string(),
+            owner: owner.to_string(),
+            image: image.to_string(),
+            views,
+            signature: vec![1, 2, 3],
+            revoke: false,
+            issued: 1,
+        }
+    }
+
+    fn views(store: &OfflineStore, recipient: &str) -> Vec<(String, String, i32)> {
+        store
+            .pending(recipient)
+            .into_iter()
+            .map(|update| (update.owner, update.image, update.views))
+            .collect()
+    }
+
+    #[test]
+    fn put_is_replayed() {
+        let path = log_path("put");
+        let mut store = OfflineStore::open(&path);
+        store.put(update("bob", "alice", "1", 3));
+        store.put(update("bob", "carol", "1", 5));
+        drop(store);
+
+        let store = OfflineStore::open(&path);
+        assert_eq!(
+            views(&store, "bob"),
+            vec![
+                ("alice".to_string(), "1".to_string(), 3),
+                ("carol".to_string(), "1".to_string(), 5)
+            ]
+        );
+        assert_eq!(store.len(), 2);
+        let _ = fs::remove_file(&path);
+    }
+
+    #[test]
+    fn ack_is_replayed() {
+        let path = log_path("ack");
+        let mut store = OfflineStore::open(&path);
+        store.put(update("bob", "alice", "1", 3));
+        store.put(update("bob", "carol", "1", 5));
+        // another owner's image with the same number stays
+        store.ack("bob", "alice", "1", 3);
+        drop(store);
+
+        let store = OfflineStore::open(&path);
+        assert_eq!(
+            views(&store, "bob"),
+            vec![("carol".to_string(), "1".to_string(), 5)]
+        );
+        let _ = fs::remove_file(&path);
+    }
+
+    #[test]
+    fn ack_of_an_older_update_keeps_the_newer_one() {
+        let path = log_path("newer");
+        let mut store = OfflineStore::open(&path);
+        store.put(update("bob", "alice", "1", 3));
+        store.put(update("bob", "alice", "1", 7));
+        store.ack("bob", "alice", "1", 3);
+        drop(store);
+
+        let store = OfflineStore::open(&path);
+        assert_eq!(
+            views(&store, "bob"),
+            vec![("alice".to_string(), "1".to_string(), 7)]
+        );
+        let _ = fs::remove_file(&path);
+    }
+
+    #[test]
+    fn compaction_keeps_only_what_is_pending() {
+        let path = log_path("compact");
+        let mut store = OfflineStore::open(&path);
+        store.put(update("bob", "alice", "1", 3));
+        store.put(update("bob", "alice", "1", 4));
+        store.put(update("dave", "alice", "2", 1));
+        store.ack("dave", "alice", "2", 1);
+        drop(store);
+
+        // opening compacts the log to one put per pending update
+        let store = OfflineStore::open(&path);
+        assert_eq!(fs::read_to_string(&path).unwrap().lines().count(), 1);
+        assert!(store.pending("dave").is_empty());
+        drop(store);
+
+        // and what is written after it is replayed on top of it
+        let mut store = OfflineStore::open(&path);
+        store.put(update("dave", "alice", "3", 2));
+        drop(store);
+        let store = OfflineStore::open(&path);
+        assert_eq!(
+            views(&store, "bob"),
+            vec![("alice".to_string(), "1".to_string(), 4)]
+        );
+        assert_eq!(
+            views(&store, "dave"),
+            vec![("alice".to_string(), "3".to_string(), 2)]
+        );
+        assert_eq!(store.recipients().len(), 2);
+        let _ = fs::remove_file(&path);
+    }
+
+    #[test]
+    fn line_cut_short_is_skipped() {
+        let path = log_path("cut");
+        let mut store = OfflineStore::open(&path);
+        store.put(update("bob", "alice", "1", 3));
+        drop(store);
+        let mut log = OpenOptions::new().append(true).open(&path).unwrap();
+        log.write_all(b"{\"Put\":{\"recipient\":\"bo").unwrap();
+        drop(log);
+
+        let store = OfflineStore::open(&path);
+        assert_eq!(store.len(), 1);
+        let _ = fs::remove_file(&path);
+    }
+}
@@ -0,0 +1,155 @@
+use crate::accounts::UserEntry;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs;
+
+// another user as this client knows it
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Peer {
+    // given the first time we see the user and never changed, so it can be picked by it
+    pub id: u32,
+    pub username: String,
+    pub display_name: String,
+    // ip and listening port, None while the user is not logged in anywhere
+    pub endpoint: Option<String>,
+    // the key the user signs with, it says who sent a message and not the ip
+    #[serde(default)]
+    pub public_key: Vec<u8>,
+    #[serde(default)]
+    pub online: bool,
+    // our own name for the user
+    #[serde(default)]
+    pub nickname: Option<String>,
+    #[serde(default)]
+    pub favorite: bool,
+}
+
+impl Peer {
+    // how the user is shown, the nickname if we gave it one
+    pub fn name(&self) -> &str {
+        self.nickname.as_deref().unwrap_or(&self.display_name)
+    }
+}
+
+// every peer this client has seen, saved to a file so ids, nicknames and favorites stay
+pub struct Peers {
+    path: String,
+    peers: BTreeMap<String, Peer>,
+}
+
+impl Peers {
+    pub fn load(path: &str) -> Peers {
+        let mut peers: BTreeMap<String, Peer> = fs::read(path)
+            .ok()
+            .and_then(|bytes| serde_json::from_slice(&bytes).ok())
+            .unwrap_or_default();
+        // the directory tells us who is online once we log in
+        for peer in peers.values_mut() {
+            peer.online = false;
+        }
+        Peers {
+            path: path.to_string(),
+            peers,
+        }
+    }
+
+    fn save(&self) {
+        if let Err(e) = fs::write(&self.path, serde_json::to_vec(&self.peers).unwrap()) {
+            println!("Could not save peers to {}: {}", self.path, e);
+        }
+    }
+
+    // add or update the peer from what the directory says, listening_port is where peers listen
+    pub fn learn(&mut self, user: &UserEntry, listening_port: u16) {
+        let next_id = self.peers.values().map(|peer| peer.id).max().unwrap_or(0) + 1;
+        let peer = self
+            .peers
+            .entry(user.username.clone())
+            .or_insert_with(|| Peer {
+                id: next_id,
+                username: user.username.clone(),
+                display_name: String::new(),
+                endpoint: None,
+                public_key: Vec::new(),
+                online: false,
+                nickname: None,
+                favorite: false,
+            });
+        peer.display_name = user.display_name.clone();
+        peer.endpoint = user.endpoint.map(|ip| format!("{}:{}", ip, listening_port));
+        peer.public_key = user.public_key.clone();
+        peer.online = user.online;
+        self.save();
+    }
+
+    // favorites first, then by username, the same order every time
+    pub fn list(&self) -> Vec<&Peer> {
+        let mut peers: Vec<&Peer> = self.peers.values().collect();
+        peers.sort_by_key(|peer| !peer.favorite);
+        peers
+    }
+
+    // a peer by its id, username or nickname
+    pub fn find(&self, name: &str) -> Option<&Peer> {
+        let name = name.trim();
+        if let Ok(id) = name.parse::<u32>() {
+            if let Some(peer) = self.peers.values().find(|peer| peer.id == id) {
+                return Some(peer);
+            }
+        }
+        self.peers.get(name).or_else(|| {
+            self.peers.values().find(|peer| {
+                peer.nickname
+                    .as_deref()
+                    .is_some_and(|nickname| nickname.eq_ignore_ascii_case(name))
+            })
+        })
+    }
+
+    pub fn get(&self, username: &str) -> Option<&Peer> {
+        self.peers.get(username)
+    }
+
+    // the peer that signs with key
+    pub fn with_key(&self, key: &[u8]) -> Option<&Peer> {
+        self.peers.values().find(|peer| peer.public_key == key)
+    }
+
+    // an empty nickname takes it away
+    pub fn set_nickname(&mut self, username: &str, nickname: &str) -> Result<(), String> {
+        let nickname = nickname.trim();
+        let taken = self.peers.values().any(|peer| {
+            peer.username != username
+                && (peer.username.eq_ignore_ascii_case(nickname)
+                    || peer
+                        .nickname
+                        .as_deref()
+                        .is_some_and(|other| other.eq_ignore_ascii_case(nickname)))
+        });
+        if taken {
+            return Err(format!("{} is already the name of another peer", nickname));
+        }
+        if nickname.parse::<u32>().is_ok() {
+            return Err("a nickname can not be a number".to_string());
+        }
+        let peer = self
+            .peers
+            .get_mut(username)
+            .ok_or_else(|| format!("{} is not a peer", username))?;
+        peer.nickname = match nickname {
+            "" => None,
+            nickname => Some(nickname.to_string()),
+        };
+        self.save();
+        Ok(())
+    }
+
+    // returns if the peer is a favorite now
+    pub fn toggle_favorite(&mut self, username: &str) -> Option<bool> {
+        let peer = self.peers.get_mut(username)?;
+        peer.favorite = !peer.favorite;
+        let favorite = peer.favorite;
+        self.save();
+        Some(favorite)
+    }
+}
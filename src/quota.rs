@@ -0,0 +1,159 @@
+use std::collections::HashMap;
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// one grant per (image id, recipient), the owner is the only one that can change it
+#[derive(Debug, Clone)]
+pub struct Grant {
+    // index of the encoded image on the owner side
+    pub source: usize,
+    pub remaining: i32,
+    // the owner took the image back, no views are given for it again
+    pub revoked: bool,
+    // views the owner gave out
+    pub consumed: i32,
+    // views the recipient said it looked at, a view that failed to open is not one
+    pub reported: i32,
+    // checksum of the copy of the encrypted image the recipient has, it needs the
+    // new one if the image was encrypted again since
+    pub copy: Vec<u8>,
+}
+
+impl Grant {
+    // every view the recipient was allowed, used or not
+    pub fn granted(&self) -> i32 {
+        self.consumed + self.remaining
+    }
+}
+
+// authoritative view counts kept by the owner of the images
+#[derive(Debug, Default)]
+pub struct ViewLedger {
+    grants: HashMap<(i32, String), Grant>,
+}
+
+impl ViewLedger {
+    pub fn new() -> ViewLedger {
+        ViewLedger {
+            grants: HashMap::new(),
+        }
+    }
+
+    pub fn grant(&mut self, image: i32, recipient: &str, source: usize, views: i32, copy: Vec<u8>) {
+        self.grants.insert(
+            (image, recipient.to_string()),
+            Grant {
+                source,
+                remaining: views,
+                revoked: false,
+                consumed: 0,
+                reported: 0,
+                copy,
+            },
+        );
+    }
+
+    // the recipient was sent another copy of the image
+    pub fn sent_copy(&mut self, image: i32, recipient: &str, copy: Vec<u8>) {
+        if let Some(grant) = self.grants.get_mut(&(image, recipient.to_string())) {
+            grant.copy = copy;
+        }
+    }
+
+    // change the views of an existing grant, returns false if it was never granted or revoked
+    pub fn set_views(&mut self, image: i32, recipient: &str, views: i32) -> bool {
+        match self.grants.get_mut(&(image, recipient.to_string())) {
+            Some(grant) if !grant.revoked => {
+                grant.remaining = views;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    // take the image back for good, returns false if it was never granted
+    pub fn revoke(&mut self, image: i32, recipient: &str) -> bool {
+        match self.grants.get_mut(&(image, recipient.to_string())) {
+            Some(grant) => {
+                grant.remaining = 0;
+                grant.revoked = true;
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn get(&self, image: i32, recipient: &str) -> Option<&Grant> {
+        self.grants.get(&(image, recipient.to_string()))
+    }
+
+    // use up one view, returns the grant after the view or None if no views are left
+    pub fn consume(&mut self, image: i32, recipient: &str) -> Option<Grant> {
+        let grant = self.grants.get_mut(&(image, recipient.to_string()))?;
+        if grant.revoked || grant.remaining <= 0 {
+            return None;
+        }
+        grant.remaining -= 1;
+        grant.consumed += 1;
+        Some(grant.clone())
+    }
+
+    // the recipient viewed the image, it can not report more views than it was given
+    pub fn report(&mut self, image: i32, recipient: &str) -> bool {
+        match self.grants.get_mut(&(image, recipient.to_string())) {
+            Some(grant) if grant.reported < grant.consumed => {
+                grant.reported += 1;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    // every grant by image and recipient
+    pub fn grants(&self) -> Vec<(i32, &str, &Grant)> {
+        let mut grants: Vec<(i32, &str, &Grant)> = self
+            .grants
+            .iter()
+            .map(|((image, recipient), grant)| (*image, recipient.as_str(), grant))
+            .collect();
+        grants.sort_by(|a, b| (a.0, a.1).cmp(&(b.0, b.1)));
+        grants
+    }
+}
+
+// append only log of every view request the owner answered
+pub struct AuditLog {
+    path: String,
+}
+
+impl AuditLog {
+    pub fn new(path: &str) -> AuditLog {
+        AuditLog {
+            path: path.to_string(),
+        }
+    }
+
+    pub fn record(&self, image: i32, recipient: &str, outcome: &str, remaining: i32) {
+        let time = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+        let line = format!(
+            "{} image={} recipient={} outcome={} remaining={}\n",
+            time, image, recipient, outcome, remaining
+        );
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path);
+        match file {
+            Ok(mut file) => {
+                if let Err(e) = file.write_all(line.as_bytes()) {
+                    println!("Failed to write audit log: {}", e);
+                }
+            }
+            Err(e) => println!("Failed to open audit log {}: {}", self.path, e),
+        }
+    }
+}
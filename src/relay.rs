@@ -0,0 +1,13 @@
+use serde::{Deserialize, Serialize};
+
+// seconds to wait for a peer to answer directly before going through the servers
+pub const DIRECT_TIMEOUT: u64 = 3;
+
+// a message for a peer the sender can not reach, the leader passes it on
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct Relayed {
+    // username of the peer
+    pub to: String,
+    // the message as it would have been sent to the peer
+    pub message: String,
+}
@@ -0,0 +1,395 @@
+use base64::Engine;
+use image::imageops::{self, FilterType};
+use image::RgbaImage;
+
+// codec ids written in the stego header
+pub const CODEC_ALPHA: u8 = 1;
+pub const CODEC_LSB: u8 = 2;
+
+// header: magic, codec id, bits per channel and payload length.
+// it is always written with 1 bit in each of r, g and b of the first pixels
+const MAGIC: &[u8; 2] = b"SG";
+const HEADER_LEN: usize = 8;
+const HEADER_PIXELS: usize = (HEADER_LEN * 8).div_ceil(3);
+
+// covers are never scaled past this many pixels on a side
+const MAX_COVER_SIDE: u32 = 8192;
+
+// a way of hiding bytes in the pixels of a cover image.
+// raw is the rgba bytes of the pixels after the header
+pub trait StegoCodec {
+    fn id(&self) -> u8;
+    fn bits(&self) -> u8;
+    // number of payload bytes that fit in this many pixels
+    fn capacity(&self, pixels: usize) -> usize;
+    fn embed(&self, raw: &mut [u8], payload: &[u8]);
+    fn extract(&self, raw: &[u8], len: usize) -> Vec<u8>;
+}
+
+// one byte per pixel in the alpha channel, like the steganography crate did
+pub struct AlphaCodec;
+
+impl StegoCodec for AlphaCodec {
+    fn id(&self) -> u8 {
+        CODEC_ALPHA
+    }
+
+    fn bits(&self) -> u8 {
+        8
+    }
+
+    fn capacity(&self, pixels: usize) -> usize {
+        pixels
+    }
+
+    fn embed(&self, raw: &mut [u8], payload: &[u8]) {
+        for (pixel, byte) in raw.chunks_mut(4).zip(payload) {
+            pixel[3] = *byte;
+        }
+    }
+
+    fn extract(&self, raw: &[u8], len: usize) -> Vec<u8> {
+        raw.chunks(4).take(len).map(|pixel| pixel[3]).collect()
+    }
+}
+
+// the lowest `bits` bits of the r, g and b channels, alpha is left alone
+pub struct LsbCodec {
+    bits: u8,
+}
+
+impl LsbCodec {
+    // plain least significant bit embedding
+    pub fn new() -> LsbCodec {
+        LsbCodec { bits: 1 }
+    }
+
+    // more bits per channel fit more data but change the cover more
+    pub fn with_bits(bits: u8) -> Option<LsbCodec> {
+        if bits == 0 || bits > 8 {
+            return None;
+        }
+        Some(LsbCodec { bits })
+    }
+}
+
+impl Default for LsbCodec {
+    fn default() -> Self {
+        LsbCodec::new()
+    }
+}
+
+impl StegoCodec for LsbCodec {
+    fn id(&self) -> u8 {
+        CODEC_LSB
+    }
+
+    fn bits(&self) -> u8 {
+        self.bits
+    }
+
+    fn capacity(&self, pixels: usize) -> usize {
+        pixels * 3 * self.bits as usize / 8
+    }
+
+    fn embed(&self, raw: &mut [u8], payload: &[u8]) {
+        write_rgb_bits(raw, payload, self.bits);
+    }
+
+    fn extract(&self, raw: &[u8], len: usize) -> Vec<u8> {
+        read_rgb_bits(raw, len, self.bits)
+    }
+}
+
+// the codec an image was (or will be) encoded with
+pub fn codec_from_id(id: u8, bits: u8) -> Option<Box<dyn StegoCodec + Send>> {
+    match id {
+        CODEC_ALPHA => Some(Box::new(AlphaCodec)),
+        CODEC_LSB => Some(Box::new(LsbCodec::with_bits(bits)?)),
+        _ => None,
+    }
+}
+
+// number of payload bytes that fit in a cover of this size, after the header
+pub fn capacity(codec: &dyn StegoCodec, width: u32, height: u32) -> usize {
+    let pixels = width as usize * height as usize;
+    if pixels < HEADER_PIXELS {
+        return 0;
+    }
+    codec.capacity(pixels - HEADER_PIXELS)
+}
+
+// pick the smallest cover the payload fits in. if none is big enough the
+// largest one is scaled up when upscale is set, otherwise it is an error
+pub fn fit_cover(
+    codec: &dyn StegoCodec,
+    covers: &[RgbaImage],
+    payload_len: usize,
+    upscale: bool,
+) -> Result<RgbaImage, String> {
+    let fitting = covers
+        .iter()
+        .filter(|cover| capacity(codec, cover.width(), cover.height()) >= payload_len)
+        .min_by_key(|cover| cover.width() as u64 * cover.height() as u64);
+    if let Some(cover) = fitting {
+        return Ok(cover.clone());
+    }
+
+    let largest = match covers
+        .iter()
+        .max_by_key(|cover| cover.width() as u64 * cover.height() as u64)
+    {
+        Some(largest) => largest,
+        None => return Err("there are no cover images".to_string()),
+    };
+    let largest_capacity = capacity(codec, largest.width(), largest.height());
+    if !upscale {
+        return Err(format!(
+            "payload of {} bytes does not fit in any cover, the largest holds {} bytes",
+            payload_len, largest_capacity
+        ));
+    }
+
+    // scale both sides by the same factor, a bit more each time until it fits
+    let mut scale = (payload_len as f64 / largest_capacity.max(1) as f64).sqrt();
+    loop {
+        let width = (largest.width() as f64 * scale).ceil() as u32;
+        let height = (largest.height() as f64 * scale).ceil() as u32;
+        if width > MAX_COVER_SIDE || height > MAX_COVER_SIDE {
+            return Err(format!(
+                "payload of {} bytes is too large even for a scaled up cover",
+                payload_len
+            ));
+        }
+        if capacity(codec, width, height) >= payload_len {
+            return Ok(imageops::resize(
+                largest,
+                width,
+                height,
+                FilterType::Triangle,
+            ));
+        }
+        scale *= 1.05;
+    }
+}
+
+// write the payload into a copy of the cover, with the header in front
+pub fn encode(
+    codec: &dyn StegoCodec,
+    cover: &RgbaImage,
+    payload: &[u8],
+) -> Result<RgbaImage, String> {
+    let pixels = (cover.width() * cover.height()) as usize;
+    if pixels < HEADER_PIXELS {
+        return Err("cover image is too small for the header".to_string());
+    }
+    let capacity = capacity(codec, cover.width(), cover.height());
+    if payload.len() > capacity {
+        return Err(format!(
+            "payload of {} bytes does not fit in cover of {} bytes",
+            payload.len(),
+            capacity
+        ));
+    }
+
+    let mut header = MAGIC.to_vec();
+    header.push(codec.id());
+    header.push(codec.bits());
+    header.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+
+    let mut image = cover.clone();
+    let (head, body) = image.split_at_mut(HEADER_PIXELS * 4);
+    write_rgb_bits(head, &header, 1);
+    codec.embed(body, payload);
+    Ok(image)
+}
+
+// put another payload of the same length in an encoded image, with the codec it was encoded with
+pub fn reencode(image: &RgbaImage, payload: &[u8]) -> Result<RgbaImage, String> {
+    let pixels = (image.width() * image.height()) as usize;
+    if pixels < HEADER_PIXELS {
+        return Err("image is too small for the header".to_string());
+    }
+    let (head, _) = image.split_at(HEADER_PIXELS * 4);
+    let header = read_rgb_bits(head, HEADER_LEN, 1);
+    if &header[..2] != MAGIC {
+        return Err("image has no stego header".to_string());
+    }
+    let codec = codec_from_id(header[2], header[3]).ok_or("unknown codec")?;
+    encode(codec.as_ref(), image, payload)
+}
+
+// png bytes of an encoded image, ready to be sent without touching the disk
+pub fn to_png(image: &RgbaImage) -> Vec<u8> {
+    let mut png = Vec::new();
+    image
+        .write_to(
+            &mut std::io::Cursor::new(&mut png),
+            image::ImageOutputFormat::Png,
+        )
+        .expect("Failed to encode png");
+    png
+}
+
+// read the payload back, images without a header are from the old alpha
+// encoding where the payload was base64 and the rest of the alpha was 0xff
+pub fn decode(image: &RgbaImage) -> Option<Vec<u8>> {
+    let pixels = (image.width() * image.height()) as usize;
+    if pixels >= HEADER_PIXELS {
+        let (head, body) = image.split_at(HEADER_PIXELS * 4);
+        let header = read_rgb_bits(head, HEADER_LEN, 1);
+        if &header[..2] == MAGIC {
+            let codec = codec_from_id(header[2], header[3])?;
+            let len = u32::from_be_bytes([header[4], header[5], header[6], header[7]]) as usize;
+            if len > codec.capacity(pixels - HEADER_PIXELS) {
+                return None;
+            }
+            return Some(codec.extract(body, len));
+        }
+    }
+
+    let clean_buffer: Vec<u8> = image
+        .pixels()
+        .map(|pixel| pixel[3])
+        .filter(|b| *b != 0xff_u8)
+        .collect();
+    base64::engine::general_purpose::STANDARD
+        .decode(clean_buffer)
+        .ok()
+}
+
+fn write_rgb_bits(raw: &mut [u8], payload: &[u8], bits: u8) {
+    let mask = ((1u16 << bits) - 1) as u8;
+    let mut channels = raw
+        .iter_mut()
+        .enumerate()
+        .filter(|(i, _)| i % 4 != 3)
+        .map(|(_, channel)| channel);
+    let mut bytes = payload.iter();
+    let mut acc: u32 = 0;
+    let mut acc_bits: u8 = 0;
+    loop {
+        while acc_bits < bits {
+            match bytes.next() {
+                Some(byte) => {
+                    acc = (acc << 8) | *byte as u32;
+                    acc_bits += 8;
+                }
+                None => break,
+            }
+        }
+        if acc_bits == 0 {
+            break;
+        }
+        // the last chunk is padded with zeros if the payload does not split evenly
+        let chunk = if acc_bits >= bits {
+            acc_bits -= bits;
+            (acc >> acc_bits) as u8 & mask
+        } else {
+            let chunk = (acc << (bits - acc_bits)) as u8 & mask;
+            acc_bits = 0;
+            chunk
+        };
+        acc &= (1 << acc_bits) - 1;
+        match channels.next() {
+            Some(channel) => *channel = (*channel & !mask) | chunk,
+            None => break,
+        }
+    }
+}
+
+fn read_rgb_bits(raw: &[u8], len: usize, bits: u8) -> Vec<u8> {
+    let mask = ((1u16 << bits) - 1) as u8;
+    let mut out = Vec::with_capacity(len);
+    let mut acc: u32 = 0;
+    let mut acc_bits: u8 = 0;
+    for (i, channel) in raw.iter().enumerate() {
+        if out.len() == len {
+            break;
+        }
+        if i % 4 == 3 {
+            continue;
+        }
+        acc = (acc << bits) | (channel & mask) as u32;
+        acc_bits += bits;
+        if acc_bits >= 8 {
+            acc_bits -= 8;
+            out.push((acc >> acc_bits) as u8);
+            acc &= (1 << acc_bits) - 1;
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cover() -> RgbaImage {
+        RgbaImage::from_fn(40, 30, |x, y| {
+            image::Rgba([(x * 7) as u8, (y * 11) as u8, (x ^ y) as u8, 200])
+        })
+    }
+
+    // every byte value, and a length that does not split evenly into most bit counts
+    fn payload(len: usize) -> Vec<u8> {
+        (0..len).map(|i| (i * 37 + 11) as u8).collect()
+    }
+
+    fn round_trip(codec: &dyn StegoCodec) {
+        let cover = cover();
+        let payload = payload(capacity(codec, cover.width(), cover.height()).min(301));
+        let image = encode(codec, &cover, &payload).unwrap();
+        assert_eq!(decode(&image).unwrap(), payload);
+    }
+
+    #[test]
+    fn alpha_round_trip() {
+        round_trip(&AlphaCodec);
+    }
+
+    #[test]
+    fn lsb_round_trip_with_every_bits() {
+        for bits in 1..=8 {
+            round_trip(&LsbCodec::with_bits(bits).unwrap());
+        }
+    }
+
+    #[test]
+    fn lsb_takes_1_to_8_bits() {
+        assert!(LsbCodec::with_bits(0).is_none());
+        assert!(LsbCodec::with_bits(9).is_none());
+    }
+
+    #[test]
+    fn full_cover_round_trip() {
+        let cover = cover();
+        for codec in [codec_from_id(CODEC_ALPHA, 8), codec_from_id(CODEC_LSB, 3)] {
+            let codec = codec.unwrap();
+            let payload = payload(capacity(codec.as_ref(), cover.width(), cover.height()));
+            let image = encode(codec.as_ref(), &cover, &payload).unwrap();
+            assert_eq!(decode(&image).unwrap(), payload);
+            let too_large = [payload, vec![0]].concat();
+            assert!(encode(codec.as_ref(), &cover, &too_large).is_err());
+        }
+    }
+
+    #[test]
+    fn reencode_keeps_the_codec() {
+        let cover = cover();
+        for bits in 1..=8 {
+            let codec = LsbCodec::with_bits(bits).unwrap();
+            let image = encode(&codec, &cover, &payload(100)).unwrap();
+            let other: Vec<u8> = payload(100).iter().map(|byte| !byte).collect();
+            let image = reencode(&image, &other).unwrap();
+            assert_eq!(decode(&image).unwrap(), other);
+        }
+    }
+
+    #[test]
+    fn lsb_leaves_alpha_alone() {
+        let cover = cover();
+        let image = encode(&LsbCodec::with_bits(8).unwrap(), &cover, &payload(500)).unwrap();
+        assert!(image.pixels().all(|pixel| pixel[3] == 200));
+    }
+}
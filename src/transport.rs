@@ -0,0 +1,357 @@
+use crate::auth::KeyRing;
+use crate::crypto;
+use crate::identity::{self, Identity};
+use hkdf::Hkdf;
+use rand::rngs::OsRng;
+use sha2::Sha256;
+use std::collections::HashMap;
+use std::io;
+use std::net::{IpAddr, SocketAddr, ToSocketAddrs, UdpSocket};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use x25519_dalek::{EphemeralSecret, PublicKey};
+
+const KEY_LEN: usize = 32;
+const SIGNATURE_LEN: usize = 64;
+
+// first byte of every datagram
+const PLAIN: u8 = 0;
+const SEALED: u8 = 1;
+
+// a new ephemeral key is made for a node after this many messages
+const REKEY_AFTER: u64 = 10000;
+// how far behind the newest message of a session one may arrive
+const REPLAY_WINDOW: u64 = 64;
+
+struct OutgoingSession {
+    ephemeral: [u8; 32],
+    key: Vec<u8>,
+    // number of the last message sent in it
+    sent: u64,
+}
+
+// the numbers of the messages a session already brought us
+struct IncomingSession {
+    key: Vec<u8>,
+    newest: u64,
+    // bit i is the message newest - i
+    window: u64,
+}
+
+impl IncomingSession {
+    // false if the message was already received or is too old to tell
+    fn accept(&mut self, number: u64) -> bool {
+        if number > self.newest {
+            let shift = number - self.newest;
+            self.window = if shift < REPLAY_WINDOW {
+                (self.window << shift) | 1
+            } else {
+                1
+            };
+            self.newest = number;
+            return true;
+        }
+        let age = self.newest - number;
+        if age >= REPLAY_WINDOW || self.window & (1 << age) != 0 {
+            return false;
+        }
+        self.window |= 1 << age;
+        true
+    }
+}
+
+// session keys, we open one with every node we send to. the sessions nodes opened
+// with us are never forgotten, so an old one can not be sent to us again
+struct Sessions {
+    outgoing: HashMap<IpAddr, OutgoingSession>,
+    incoming: HashMap<(IpAddr, [u8; 32]), IncomingSession>,
+}
+
+// what a message is signed as, with the session and its number in it so it can not be
+// sent again in another one. plain messages have neither
+fn signed_bytes(ephemeral: &[u8; 32], number: u64, message: &[u8]) -> Vec<u8> {
+    let mut bytes = ephemeral.to_vec();
+    bytes.extend_from_slice(&number.to_be_bytes());
+    bytes.extend_from_slice(message);
+    bytes
+}
+
+fn session_key(shared: &[u8], ephemeral: &[u8; 32], recipient: &PublicKey) -> Vec<u8> {
+    let mut info = b"session".to_vec();
+    info.extend_from_slice(ephemeral);
+    info.extend_from_slice(recipient.as_bytes());
+    let mut key = vec![0; crypto::KEY_LEN];
+    Hkdf::<Sha256>::new(None, shared)
+        .expand(&info, &mut key)
+        .unwrap();
+    key
+}
+
+// a udp socket that signs everything it sends and encrypts it for the receiving node
+// once its key is known. anything received that is not signed by the key the sender
+// is known by is dropped.
+//
+// sending to a node opens a session with a fresh x25519 key against the node's own key,
+// the ephemeral public key goes in front of every message so the node can derive
+// the same session key. every message of a session is numbered, one that was already
+// received is dropped. nodes we have no key for yet only get signed messages,
+// that is the first request to a server and the first election round. a node that sent us
+// an encrypted message knows our key, so plain messages from it are dropped after that
+pub struct SecureSocket {
+    socket: UdpSocket,
+    identity: Arc<Identity>,
+    keyring: Arc<Mutex<KeyRing>>,
+    sessions: Arc<Mutex<Sessions>>,
+}
+
+impl SecureSocket {
+    pub fn new(
+        socket: UdpSocket,
+        identity: Arc<Identity>,
+        keyring: Arc<Mutex<KeyRing>>,
+    ) -> SecureSocket {
+        SecureSocket {
+            socket,
+            identity,
+            keyring,
+            sessions: Arc::new(Mutex::new(Sessions {
+                outgoing: HashMap::new(),
+                incoming: HashMap::new(),
+            })),
+        }
+    }
+
+    pub fn send_to<A: ToSocketAddrs>(&self, buf: &[u8], addr: A) -> io::Result<usize> {
+        let addr = addr
+            .to_socket_addrs()?
+            .next()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "no address to send to"))?;
+        // our public key, the signature and then the message,
+        // sealed with the number of the message in front
+        let mut datagram = Vec::new();
+        match self.outgoing_session(addr.ip()) {
+            Some((ephemeral, key, number)) => {
+                let mut sealed = number.to_be_bytes().to_vec();
+                sealed.extend_from_slice(&self.identity.public_key());
+                sealed
+                    .extend_from_slice(&self.identity.sign(&signed_bytes(&ephemeral, number, buf)));
+                sealed.extend_from_slice(buf);
+                datagram.push(SEALED);
+                datagram.extend_from_slice(&ephemeral);
+                datagram.extend_from_slice(&crypto::seal(&key, &sealed));
+            }
+            None => {
+                datagram.push(PLAIN);
+                datagram.extend_from_slice(&self.identity.public_key());
+                datagram.extend_from_slice(&self.identity.sign(&signed_bytes(&[0; 32], 0, buf)));
+                datagram.extend_from_slice(buf);
+            }
+        }
+        self.socket.send_to(&datagram, addr)?;
+        Ok(buf.len())
+    }
+
+    // waits for the next properly signed message and puts only the message in buf
+    pub fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        let (len, src, _) = self.recv_from_key(buf)?;
+        Ok((len, src))
+    }
+
+    // the same, along with the key the message is signed with
+    pub fn recv_from_key(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr, Vec<u8>)> {
+        let mut datagram = vec![0; 65535];
+        loop {
+            let (amt, src) = self.socket.recv_from(&mut datagram)?;
+            // the session it came in and the number of the message in it, none for plain ones
+            let (session, number, signed) = match datagram[..amt].split_first() {
+                Some((&PLAIN, signed)) => (None, 0, signed.to_vec()),
+                Some((&SEALED, sealed)) if sealed.len() >= 32 => {
+                    let (ephemeral, sealed) = sealed.split_at(32);
+                    let ephemeral = <[u8; 32]>::try_from(ephemeral).unwrap();
+                    let key = self.incoming_session(src.ip(), ephemeral);
+                    match crypto::open(&key, sealed) {
+                        Some(opened) if opened.len() >= 8 => {
+                            let (number, signed) = opened.split_at(8);
+                            let number = u64::from_be_bytes(number.try_into().unwrap());
+                            (Some((ephemeral, key)), number, signed.to_vec())
+                        }
+                        _ => {
+                            println!("Dropped message that does not decrypt from {}", src);
+                            continue;
+                        }
+                    }
+                }
+                _ => {
+                    println!("Dropped unknown message from {}", src);
+                    continue;
+                }
+            };
+            if signed.len() < KEY_LEN + SIGNATURE_LEN {
+                println!("Dropped unsigned message from {}", src);
+                continue;
+            }
+            let (key, rest) = signed.split_at(KEY_LEN);
+            let (signature, message) = rest.split_at(SIGNATURE_LEN);
+            let bytes = signed_bytes(
+                &session
+                    .as_ref()
+                    .map_or([0; 32], |(ephemeral, _)| *ephemeral),
+                number,
+                message,
+            );
+            if !identity::verify(key, &bytes, signature) {
+                println!("Dropped message with a bad signature from {}", src);
+                continue;
+            }
+            let mut keyring = self.keyring.lock().unwrap();
+            if session.is_none() && keyring.seals(key) {
+                println!("Dropped unencrypted message from {}, it knows our key", src);
+                continue;
+            }
+            if !keyring.check(src.ip(), key) {
+                println!("Dropped spoofed message from {}", src);
+                continue;
+            }
+            drop(keyring);
+            if let Some((ephemeral, session_key)) = session {
+                if !self.accept(src.ip(), ephemeral, session_key, number) {
+                    println!(
+                        "Dropped message {} from {} that was received before",
+                        number, src
+                    );
+                    continue;
+                }
+                self.keyring.lock().unwrap().sealed_by(key);
+            }
+            let len = message.len().min(buf.len());
+            buf[..len].copy_from_slice(&message[..len]);
+            return Ok((len, src, key.to_vec()));
+        }
+    }
+
+    pub fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.socket.set_read_timeout(timeout)
+    }
+
+    pub fn try_clone(&self) -> io::Result<SecureSocket> {
+        Ok(SecureSocket {
+            socket: self.socket.try_clone()?,
+            identity: Arc::clone(&self.identity),
+            keyring: Arc::clone(&self.keyring),
+            sessions: Arc::clone(&self.sessions),
+        })
+    }
+
+    // ephemeral public key, session key and number of the next message to send to ip with,
+    // None if we have no key for it
+    fn outgoing_session(&self, ip: IpAddr) -> Option<([u8; 32], Vec<u8>, u64)> {
+        let recipient = identity::exchange_key(self.keyring.lock().unwrap().get(&ip)?)?;
+        let mut sessions = self.sessions.lock().unwrap();
+        let session = sessions.outgoing.get_mut(&ip);
+        match session {
+            Some(session) if session.sent < REKEY_AFTER => {
+                session.sent += 1;
+                Some((session.ephemeral, session.key.clone(), session.sent))
+            }
+            _ => {
+                let secret = EphemeralSecret::random_from_rng(OsRng);
+                let ephemeral = PublicKey::from(&secret).to_bytes();
+                let shared = secret.diffie_hellman(&recipient);
+                let key = session_key(shared.as_bytes(), &ephemeral, &recipient);
+                sessions.outgoing.insert(
+                    ip,
+                    OutgoingSession {
+                        ephemeral,
+                        key: key.clone(),
+                        sent: 1,
+                    },
+                );
+                Some((ephemeral, key, 1))
+            }
+        }
+    }
+
+    // session key for a message sealed with ephemeral, derived only for a new session.
+    // it is only kept once a properly signed message came in it
+    fn incoming_session(&self, ip: IpAddr, ephemeral: [u8; 32]) -> Vec<u8> {
+        if let Some(session) = self.sessions.lock().unwrap().incoming.get(&(ip, ephemeral)) {
+            return session.key.clone();
+        }
+        let secret = self.identity.exchange_secret();
+        let shared = secret.diffie_hellman(&PublicKey::from(ephemeral));
+        session_key(shared.as_bytes(), &ephemeral, &PublicKey::from(&secret))
+    }
+
+    // false if message number of the session was received before
+    fn accept(&self, ip: IpAddr, ephemeral: [u8; 32], key: Vec<u8>, number: u64) -> bool {
+        let mut sessions = self.sessions.lock().unwrap();
+        let session = sessions
+            .incoming
+            .entry((ip, ephemeral))
+            .or_insert(IncomingSession {
+                key,
+                newest: 0,
+                window: 0,
+            });
+        session.accept(number)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn session() -> IncomingSession {
+        IncomingSession {
+            key: Vec::new(),
+            newest: 0,
+            window: 0,
+        }
+    }
+
+    #[test]
+    fn duplicate_is_dropped() {
+        let mut session = session();
+        assert!(session.accept(1));
+        assert!(!session.accept(1));
+        assert!(session.accept(2));
+        assert!(!session.accept(2));
+        assert!(!session.accept(1));
+    }
+
+    #[test]
+    fn late_message_in_the_window_is_taken_once() {
+        let mut session = session();
+        assert!(session.accept(10));
+        assert!(session.accept(7));
+        assert!(!session.accept(7));
+        assert!(session.accept(9));
+    }
+
+    #[test]
+    fn message_behind_the_window_is_dropped() {
+        let mut session = session();
+        assert!(session.accept(REPLAY_WINDOW + 10));
+        // the oldest number the window still covers
+        assert!(session.accept(11));
+        assert!(!session.accept(10));
+        assert!(!session.accept(1));
+    }
+
+    #[test]
+    fn window_shifts_with_the_newest_message() {
+        let mut session = session();
+        assert!(session.accept(1));
+        assert!(session.accept(3));
+        // shifted by less than the window, what was received is still remembered
+        assert!(session.accept(20));
+        assert!(!session.accept(1));
+        assert!(!session.accept(3));
+        assert!(session.accept(2));
+        // shifted past the window, everything before it is too old
+        assert!(session.accept(20 + REPLAY_WINDOW));
+        assert!(!session.accept(20));
+        assert!(session.accept(21));
+        assert!(!session.accept(21));
+    }
+}